@@ -0,0 +1,49 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Guarded handles to a store's reader and writer SQLite connections.
+
+use std::sync::MutexGuard;
+
+/// A handle to the read-only (or read-write, when reading without
+/// `concurrent` set) connection for a store.
+pub struct Reader<'a>(pub(super) MutexGuard<'a, rusqlite::Connection>);
+
+impl<'a> Reader<'a> {
+    /// Runs `f` with the underlying connection.
+    pub fn read<T, E>(&self, f: impl FnOnce(&rusqlite::Connection) -> Result<T, E>) -> Result<T, E>
+    where
+        E: From<rusqlite::Error>,
+    {
+        f(&self.0)
+    }
+}
+
+/// A handle to the single read-write connection for a store.
+pub struct Writer<'a>(pub(super) MutexGuard<'a, rusqlite::Connection>);
+
+impl<'a> Writer<'a> {
+    /// Runs `f` inside a transaction, committing if it returns `Ok`
+    /// and rolling back if it returns `Err` or panics.
+    pub fn write<T, E>(
+        &self,
+        f: impl FnOnce(&rusqlite::Transaction<'_>) -> Result<T, E>,
+    ) -> Result<T, E>
+    where
+        E: From<rusqlite::Error>,
+    {
+        let tx = self.0.unchecked_transaction().map_err(E::from)?;
+        let value = f(&tx)?;
+        tx.commit().map_err(E::from)?;
+        Ok(value)
+    }
+
+    /// Gives `f` direct, non-transactional access to the connection.
+    ///
+    /// This is for operations like backup and restore that manage their
+    /// own transactional semantics.
+    pub fn with_conn<T, E>(&self, f: impl FnOnce(&rusqlite::Connection) -> Result<T, E>) -> Result<T, E> {
+        f(&self.0)
+    }
+}
@@ -4,20 +4,29 @@
 
 //! A single database in a store.
 
-use std::{borrow::Borrow, ops::RangeBounds};
+use std::{borrow::Borrow, collections::HashMap, ops::ControlFlow, ops::RangeBounds};
 
-use rusqlite::ToSql;
+use rusqlite::{types::ValueRef, ToSql};
 
 use crate::skv::{
+    connection::Writer,
     key::Key,
-    sql::RangeFragment,
-    store::{Store, StoreError},
-    value::Value,
+    schema,
+    sql::{self, PrefixFragment, RangeFragment},
+    store::{ChangeRecord, Store, StoreError},
+    value::{Value, ValueError},
 };
 
 struct Put<'a>(&'a Key, &'a Value);
 struct Delete<'a>(&'a Key);
 
+/// A single local write, as returned by [`Database::changes_since`].
+#[derive(Clone, Debug)]
+pub enum Change {
+    Put(Key, Value),
+    Delete(Key),
+}
+
 /// A data access object for reading and writing
 /// keys and values in a named logical database.
 pub struct Database<'a> {
@@ -46,12 +55,28 @@ impl<'a> Database<'a> {
         })
     }
 
-    pub fn put<K, V>(&self, pairs: &[(K, Option<V>)]) -> Result<(), DatabaseError>
+    pub fn put<K, V>(
+        &self,
+        pairs: &[(K, Option<V>)],
+        quota: &QuotaOptions,
+    ) -> Result<(), DatabaseError>
     where
         K: Borrow<Key>,
         V: Borrow<Value>,
     {
-        let (updates, deletions) = pairs.iter().fold(
+        let (updates, deletions) = Self::partition_pairs(pairs);
+        self.put_or_delete(&updates, &deletions, Some(quota))
+    }
+
+    /// Splits `pairs` into the `Put`s and `Delete`s
+    /// [`Database::put_or_delete`] (and [`Recorder::put`]) expect,
+    /// treating a `None` value as a delete.
+    fn partition_pairs<'p, K, V>(pairs: &'p [(K, Option<V>)]) -> (Vec<Put<'p>>, Vec<Delete<'p>>)
+    where
+        K: Borrow<Key>,
+        V: Borrow<Value>,
+    {
+        pairs.iter().fold(
             (Vec::new(), Vec::new()),
             |(mut updates, mut deletions), (key, value)| {
                 match value {
@@ -60,36 +85,907 @@ impl<'a> Database<'a> {
                 }
                 (updates, deletions)
             },
-        );
-        self.put_or_delete(&updates, &deletions)
+        )
+    }
+
+    /// Sums `key.len() + json_len(value)` for every entry in `keys`, or
+    /// for every entry in this database when `keys` is `None`.
+    ///
+    /// Mirrors the byte-accounting the webext_storage component's
+    /// `storage.sync` quota model uses to decide whether a write fits.
+    pub fn bytes_in_use(&self, keys: Option<&[Key]>) -> Result<u64, DatabaseError> {
+        let reader = self.store.reader()?;
+        Ok(reader.read(|conn| match keys {
+            Some(keys) => {
+                let mut total = 0u64;
+                sql::each_chunk(keys, 1, |chunk| {
+                    total += Self::sum_entry_bytes(conn, &self.name, chunk)?;
+                    Ok(())
+                })?;
+                Ok(total)
+            }
+            None => {
+                let mut statement = conn.prepare_cached(
+                    "SELECT key, json(value) AS value
+                     FROM data
+                     WHERE db_id = (SELECT id FROM dbs WHERE name = :name)",
+                )?;
+                let mut rows =
+                    statement.query(rusqlite::named_params! { ":name": &self.name })?;
+                let mut total = 0u64;
+                while let Some(row) = rows.next()? {
+                    let key = row.get::<_, Key>("key")?;
+                    let value = row.get::<_, Value>("value")?;
+                    total += Self::entry_bytes(&key, &value);
+                }
+                Ok(total)
+            }
+        })?)
+    }
+
+    fn sum_entry_bytes(
+        conn: &rusqlite::Connection,
+        name: &str,
+        keys: &[Key],
+    ) -> rusqlite::Result<u64> {
+        let placeholders = (0..keys.len())
+            .map(|i| format!(":key{i}"))
+            .collect::<Vec<_>>();
+        let mut statement = conn.prepare_cached(&format!(
+            "SELECT key, json(value) AS value
+             FROM data
+             WHERE
+               db_id = (SELECT id FROM dbs WHERE name = :name)
+               AND key IN ({})",
+            placeholders.join(", "),
+        ))?;
+        let mut params: Vec<(&str, &dyn ToSql)> = Vec::with_capacity(keys.len() + 1);
+        params.push((":name", &name as &dyn ToSql));
+        for (name, key) in placeholders.iter().zip(keys) {
+            params.push((name.as_str(), key as &dyn ToSql));
+        }
+        let mut rows = statement.query(params.as_slice())?;
+        let mut total = 0u64;
+        while let Some(row) = rows.next()? {
+            let key = row.get::<_, Key>("key")?;
+            let value = row.get::<_, Value>("value")?;
+            total += Self::entry_bytes(&key, &value);
+        }
+        Ok(total)
+    }
+
+    fn entry_bytes(key: &Key, value: &Value) -> u64 {
+        (key.as_str().len() + value.json_len()) as u64
     }
 
     pub fn delete(&self, key: &Key) -> Result<(), DatabaseError> {
-        self.put_or_delete(&[], &[Delete(key)])
+        self.put_or_delete(&[], &[Delete(key)], None)
     }
 
-    pub fn clear(&self) -> Result<(), DatabaseError> {
+    /// Inserts `value` for `key` only if `key` isn't already present.
+    ///
+    /// Returns whether the value was inserted.
+    pub fn put_if_absent(
+        &self,
+        key: &Key,
+        value: &Value,
+        quota: &QuotaOptions,
+    ) -> Result<bool, DatabaseError> {
+        self.compare_and_swap(key, None, Some(value), quota)
+    }
+
+    /// Writes `new` for `key` only if the value currently stored for
+    /// `key` equals `expected` (`None` meaning "must be absent").
+    ///
+    /// Runs the read-compare-write in a single transaction, so it's
+    /// race-free against concurrent writers. Returns whether the swap
+    /// happened.
+    ///
+    /// A successful swap goes through [`Database::put_or_delete_tx`], so
+    /// it's stamped with a `local_change_counter`, tombstoned if it's a
+    /// delete, subject to `quota` if it's a put, and reported to
+    /// observers, exactly like a write made through [`Database::put`] or
+    /// [`Database::delete`].
+    pub fn compare_and_swap(
+        &self,
+        key: &Key,
+        expected: Option<&Value>,
+        new: Option<&Value>,
+        quota: &QuotaOptions,
+    ) -> Result<bool, DatabaseError> {
+        let writer = self.store.writer()?;
+        let changes = writer.write(|tx| {
+            let current = self.current_value_tx(tx, key)?;
+
+            if current.as_ref() != expected {
+                return Ok(None);
+            }
+
+            Ok(Some(match new {
+                Some(new) => self.put_or_delete_tx(tx, &[Put(key, new)], &[], Some(quota))?,
+                None => self.put_or_delete_tx(tx, &[], &[Delete(key)], None)?,
+            }))
+        })?;
+
+        let swapped = changes.is_some();
+        if let Some(changes) = changes {
+            self.store.notify(&changes);
+        }
+        Ok(swapped)
+    }
+
+    /// Returns every put or delete made to this database after
+    /// `counter` (exclusive), ordered by when it happened, along with
+    /// the change counter it was stamped with.
+    ///
+    /// A sync engine can use this to find local changes to upload;
+    /// neither [`Database::sync`] nor [`crate::skv::sync`] calls it.
+    pub fn changes_since(&self, counter: i64) -> Result<Vec<(i64, Change)>, DatabaseError> {
+        let reader = self.store.reader()?;
+        reader.read(|conn| {
+            let mut statement = conn.prepare_cached(
+                "SELECT
+                   v.key AS key,
+                   json(v.value) AS value,
+                   v.local_change_counter AS counter
+                 FROM data v
+                 JOIN dbs d ON d.id = v.db_id
+                 WHERE d.name = :name AND v.local_change_counter > :counter
+                 UNION ALL
+                 SELECT
+                   t.key AS key,
+                   NULL AS value,
+                   t.local_change_counter AS counter
+                 FROM tombstones t
+                 JOIN dbs d ON d.id = t.db_id
+                 WHERE d.name = :name AND t.local_change_counter > :counter
+                 ORDER BY counter ASC
+                ",
+            )?;
+            let changes = statement
+                .query(rusqlite::named_params! { ":name": &self.name, ":counter": counter })?
+                .mapped(|row| {
+                    let key = row.get::<_, Key>("key")?;
+                    let counter = row.get::<_, i64>("counter")?;
+                    let change = match row.get::<_, Option<Value>>("value")? {
+                        Some(value) => Change::Put(key, value),
+                        None => Change::Delete(key),
+                    };
+                    Ok((counter, change))
+                })
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(changes)
+        })
+    }
+
+    /// Returns the store's current local change counter, i.e. the
+    /// counter that was (or will be) stamped on the most recent write.
+    pub fn local_change_counter(&self) -> Result<i64, DatabaseError> {
+        let reader = self.store.reader()?;
+        Ok(reader.read(|conn| {
+            conn.query_row(
+                "SELECT next - 1 FROM local_change_counter WHERE id = 0",
+                [],
+                |row| row.get(0),
+            )
+        })?)
+    }
+
+    /// Returns size and count statistics for this database's underlying
+    /// store.
+    ///
+    /// `pair_count` is specific to this named database, but `file_size`,
+    /// `wal_size`, and `page_size` describe the whole physical SQLite
+    /// file, since that's shared by every named database in the store.
+    pub fn stats(&self) -> Result<Stats, DatabaseError> {
+        let writer = self.store.writer()?;
+        let (page_count, page_size, pair_count) = writer.with_conn(|conn| {
+            let page_count: u64 = conn.pragma_query_value(None, "page_count", |row| row.get(0))?;
+            let page_size: u64 = conn.pragma_query_value(None, "page_size", |row| row.get(0))?;
+            let pair_count: u64 = conn.query_row(
+                "SELECT COUNT(*)
+                 FROM data v
+                 JOIN dbs d ON d.id = v.db_id
+                 WHERE d.name = :name",
+                rusqlite::named_params! { ":name": &self.name },
+                |row| row.get(0),
+            )?;
+            Ok::<_, rusqlite::Error>((page_count, page_size, pair_count))
+        })?;
+        let wal_size = self.store.wal_size()?;
+        Ok(Stats {
+            pair_count,
+            file_size: page_count * page_size,
+            wal_size,
+            page_size,
+        })
+    }
+
+    /// Returns the last sync timestamp recorded for this database by
+    /// [`Database::set_last_sync`], or `0` if it's never synced.
+    ///
+    /// Used by [`crate::skv::sync`].
+    pub fn last_sync(&self) -> Result<i64, DatabaseError> {
+        let reader = self.store.reader()?;
+        Ok(reader.read(|conn| {
+            conn.query_row(
+                "SELECT s.last_sync
+                 FROM sync_meta s
+                 JOIN dbs d ON d.id = s.db_id
+                 WHERE d.name = :name",
+                rusqlite::named_params! { ":name": &self.name },
+                |row| row.get(0),
+            )
+            .or(Ok::<_, rusqlite::Error>(0))
+        })?)
+    }
+
+    /// Records `last_sync` as the time of the most recently completed
+    /// sync, creating the database's `sync_meta` row if it doesn't
+    /// already exist.
+    pub fn set_last_sync(&self, last_sync: i64) -> Result<(), DatabaseError> {
         let writer = self.store.writer()?;
         writer.write(|tx| {
-            let mut statement = tx.prepare_cached("DELETE FROM dbs WHERE name = :name")?;
-            statement.execute(rusqlite::named_params! {
-                ":name": self.name,
-            })?;
+            self.ensure_sync_meta_row(tx)?;
+            tx.execute(
+                "UPDATE sync_meta
+                 SET last_sync = :last_sync
+                 WHERE db_id = (SELECT id FROM dbs WHERE name = :name)",
+                rusqlite::named_params! { ":name": &self.name, ":last_sync": last_sync },
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Returns this database's sync engine GUID, or `None` if it hasn't
+    /// synced yet.
+    pub fn sync_id(&self) -> Result<Option<String>, DatabaseError> {
+        let reader = self.store.reader()?;
+        Ok(reader.read(|conn| {
+            conn.query_row(
+                "SELECT s.sync_id
+                 FROM sync_meta s
+                 JOIN dbs d ON d.id = s.db_id
+                 WHERE d.name = :name",
+                rusqlite::named_params! { ":name": &self.name },
+                |row| row.get(0),
+            )
+            .or(Ok::<_, rusqlite::Error>(None))
+        })?)
+    }
+
+    /// Sets this database's sync engine GUID, and resets its last sync
+    /// timestamp, so the next sync re-fetches everything from the new
+    /// collection.
+    pub fn set_sync_id(&self, sync_id: &str) -> Result<(), DatabaseError> {
+        let writer = self.store.writer()?;
+        writer.write(|tx| {
+            self.ensure_sync_meta_row(tx)?;
+            tx.execute(
+                "UPDATE sync_meta
+                 SET sync_id = :sync_id, last_sync = 0
+                 WHERE db_id = (SELECT id FROM dbs WHERE name = :name)",
+                rusqlite::named_params! { ":name": &self.name, ":sync_id": sync_id },
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Stages `records` fetched from the server for a later call to
+    /// [`Database::apply_incoming`] to merge.
+    ///
+    /// Writes to `incoming`, not `mirror`: `mirror` is the three-way
+    /// merge base [`Database::sync`] reads, and staging here instead
+    /// keeps `mirror` at its pre-sync value until `apply_incoming`
+    /// actually runs the merge, even if `store_incoming` is called
+    /// more than once beforehand (as the bridged sync protocol does).
+    pub fn store_incoming(&self, records: &[IncomingRecord]) -> Result<(), DatabaseError> {
+        for record in records {
+            if let Some(value) = &record.value {
+                value.ensure_scalar()?;
+            }
+        }
+
+        let writer = self.store.writer()?;
+        writer.write(|tx| {
+            self.ensure_sync_meta_row(tx)?;
+            for record in records {
+                tx.execute(
+                    "INSERT INTO incoming(db_id, key, value, server_modified)
+                     VALUES(
+                       (SELECT id FROM dbs WHERE name = :name),
+                       :key,
+                       jsonb(:value),
+                       :server_modified
+                     )
+                     ON CONFLICT DO UPDATE SET
+                       value = excluded.value,
+                       server_modified = excluded.server_modified",
+                    rusqlite::named_params! {
+                        ":name": &self.name,
+                        ":key": &record.key,
+                        ":value": &record.value,
+                        ":server_modified": record.server_modified,
+                    },
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Merges every record staged by [`Database::store_incoming`] since
+    /// the last call, via [`Database::sync`] (using [`RemoteWins`] to
+    /// resolve genuine conflicts), then clears the staged rows, and
+    /// returns the records `sync` determined still need to be uploaded.
+    ///
+    /// Delegating to `sync` (rather than unconditionally overwriting
+    /// `data` with the incoming value) means a key this device changed
+    /// locally but the server didn't touch is preserved instead of
+    /// clobbered, while a genuine conflict still falls back to the
+    /// server's value, matching this method's previous behavior. The
+    /// returned records must come from `sync`'s own return value, not a
+    /// `changes_since` snapshot taken before the merge runs: for a key
+    /// that genuinely conflicts, `sync` overwrites `data`/`mirror` with
+    /// the resolved value, and a pre-merge snapshot would still carry
+    /// the local value that was just discarded.
+    pub fn apply_incoming(&self) -> Result<Vec<OutgoingRecord>, DatabaseError> {
+        let staged = {
+            let reader = self.store.reader()?;
+            reader.read(|conn| {
+                let mut statement = conn.prepare_cached(
+                    "SELECT
+                       i.key AS key,
+                       json(i.value) AS value,
+                       i.server_modified AS server_modified
+                     FROM incoming i
+                     JOIN dbs d ON d.id = i.db_id
+                     WHERE d.name = :name",
+                )?;
+                statement
+                    .query(rusqlite::named_params! { ":name": &self.name })?
+                    .mapped(|row| {
+                        Ok(IncomingRecord {
+                            key: row.get::<_, Key>("key")?,
+                            value: row.get::<_, Option<Value>>("value")?,
+                            server_modified: row.get::<_, i64>("server_modified")?,
+                        })
+                    })
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            })?
+        };
+
+        let outgoing = self.sync(&staged, &mut RemoteWins)?;
+
+        let writer = self.store.writer()?;
+        writer.write(|tx| {
+            tx.execute(
+                "DELETE FROM incoming WHERE db_id = (SELECT id FROM dbs WHERE name = :name)",
+                rusqlite::named_params! { ":name": &self.name },
+            )?;
+            Ok(())
+        })?;
+
+        Ok(outgoing.records)
+    }
+
+    /// Records that the server has accepted `keys` as of
+    /// `server_modified`, so a later [`Database::apply_incoming`] merge
+    /// doesn't treat our own upload as a server-side change.
+    pub fn set_uploaded(&self, server_modified: i64, keys: &[Key]) -> Result<(), DatabaseError> {
+        let writer = self.store.writer()?;
+        writer.write(|tx| {
+            for key in keys {
+                tx.execute(
+                    "UPDATE mirror
+                     SET server_modified = :server_modified
+                     WHERE
+                       db_id = (SELECT id FROM dbs WHERE name = :name)
+                       AND key = :key",
+                    rusqlite::named_params! {
+                        ":name": &self.name,
+                        ":key": key,
+                        ":server_modified": server_modified,
+                    },
+                )?;
+            }
             Ok(())
         })
     }
 
+    /// Forgets this database's sync engine GUID, mirrored server state,
+    /// and any not-yet-applied staged records, without touching its
+    /// local data. The next sync starts over as if this database had
+    /// never synced.
+    pub fn reset_sync(&self) -> Result<(), DatabaseError> {
+        let writer = self.store.writer()?;
+        writer.write(|tx| {
+            tx.execute(
+                "DELETE FROM mirror WHERE db_id = (SELECT id FROM dbs WHERE name = :name)",
+                rusqlite::named_params! { ":name": &self.name },
+            )?;
+            tx.execute(
+                "DELETE FROM incoming WHERE db_id = (SELECT id FROM dbs WHERE name = :name)",
+                rusqlite::named_params! { ":name": &self.name },
+            )?;
+            tx.execute(
+                "UPDATE sync_meta
+                 SET sync_id = NULL, last_sync = 0
+                 WHERE db_id = (SELECT id FROM dbs WHERE name = :name)",
+                rusqlite::named_params! { ":name": &self.name },
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Runs one incremental sync round: three-way merges `incoming`
+    /// against the mirrored server state and the current local data,
+    /// applies the merged results, advances the stored sync token, and
+    /// returns the records that still need to be uploaded.
+    ///
+    /// For each key in `incoming`, `base` is the value last acknowledged
+    /// by the server (the mirror), `local` is the current value in
+    /// `data`, and `remote` is the incoming value:
+    ///
+    /// - If `local == base`, only the remote side changed: take `remote`.
+    /// - If `remote == base`, only the local side changed: keep `local`.
+    /// - If `local == remote`, both sides made the same change: no-op.
+    /// - Otherwise, both sides changed differently: ask `reconciler`.
+    ///
+    /// Only keys in `incoming` are merged, and only they can appear in
+    /// the returned records: a local change to a key that isn't in
+    /// `incoming` is not returned.
+    ///
+    /// Runs as a single write transaction, so an interrupted sync leaves
+    /// `mirror` and the sync token exactly where the last successful
+    /// round left them, and the next sync re-merges cleanly from there.
+    ///
+    /// Resolved writes to `data` go through [`Database::put_or_delete_tx`],
+    /// the same path [`Database::put`]/[`Database::delete`] use, so a
+    /// resolved deletion is tombstoned (instead of just disappearing from
+    /// `data`, invisible to a later [`Database::changes_since`]), and
+    /// every resolved write is reported to observers, exactly like a
+    /// write made outside of a sync.
+    pub fn sync(
+        &self,
+        incoming: &[IncomingRecord],
+        reconciler: &mut impl Reconciler,
+    ) -> Result<Outgoing, DatabaseError> {
+        let writer = self.store.writer()?;
+        let (outgoing, changes) = writer.write(|tx| {
+            self.ensure_sync_meta_row(tx)?;
+
+            let keys = incoming.iter().map(|record| &record.key).collect::<Vec<_>>();
+
+            let mut base = HashMap::new();
+            sql::each_chunk(&keys, 1, |chunk| {
+                let placeholders = (0..chunk.len())
+                    .map(|i| format!(":key{i}"))
+                    .collect::<Vec<_>>();
+                let mut statement = tx.prepare_cached(&format!(
+                    "SELECT key, json(value) AS value
+                     FROM mirror
+                     WHERE
+                       db_id = (SELECT id FROM dbs WHERE name = :name)
+                       AND key IN ({})",
+                    placeholders.join(", "),
+                ))?;
+                let mut params: Vec<(&str, &dyn ToSql)> = Vec::with_capacity(chunk.len() + 1);
+                params.push((":name", &self.name as &dyn ToSql));
+                for (name, key) in placeholders.iter().zip(chunk) {
+                    params.push((name.as_str(), *key as &dyn ToSql));
+                }
+                let mut rows = statement.query(params.as_slice())?;
+                while let Some(row) = rows.next()? {
+                    base.insert(
+                        row.get::<_, Key>("key")?,
+                        row.get::<_, Option<Value>>("value")?,
+                    );
+                }
+                Ok(())
+            })?;
+
+            let mut local = HashMap::new();
+            sql::each_chunk(&keys, 1, |chunk| {
+                let placeholders = (0..chunk.len())
+                    .map(|i| format!(":key{i}"))
+                    .collect::<Vec<_>>();
+                let mut statement = tx.prepare_cached(&format!(
+                    "SELECT key, json(value) AS value
+                     FROM data
+                     WHERE
+                       db_id = (SELECT id FROM dbs WHERE name = :name)
+                       AND key IN ({})",
+                    placeholders.join(", "),
+                ))?;
+                let mut params: Vec<(&str, &dyn ToSql)> = Vec::with_capacity(chunk.len() + 1);
+                params.push((":name", &self.name as &dyn ToSql));
+                for (name, key) in placeholders.iter().zip(chunk) {
+                    params.push((name.as_str(), *key as &dyn ToSql));
+                }
+                let mut rows = statement.query(params.as_slice())?;
+                while let Some(row) = rows.next()? {
+                    local.insert(row.get::<_, Key>("key")?, row.get::<_, Value>("value")?);
+                }
+                Ok(())
+            })?;
+
+            let mut outgoing = Vec::new();
+            let mut max_server_modified = None;
+            let mut resolved_writes: Vec<(Key, Option<Value>)> = Vec::new();
+
+            for record in incoming {
+                let base_value = base.get(&record.key).cloned().flatten();
+                let local_value = local.get(&record.key).cloned();
+                let remote_value = record.value.clone();
+
+                let resolved = if local_value == base_value {
+                    remote_value.clone()
+                } else if remote_value == base_value {
+                    local_value.clone()
+                } else if local_value == remote_value {
+                    local_value.clone()
+                } else {
+                    reconciler.reconcile(
+                        &record.key,
+                        base_value.as_ref(),
+                        local_value.as_ref(),
+                        remote_value.as_ref(),
+                    )
+                };
+
+                if resolved != local_value {
+                    resolved_writes.push((record.key.clone(), resolved.clone()));
+                }
+
+                tx.execute(
+                    "INSERT INTO mirror(db_id, key, value, server_modified)
+                     VALUES(
+                       (SELECT id FROM dbs WHERE name = :name),
+                       :key,
+                       jsonb(:value),
+                       :server_modified
+                     )
+                     ON CONFLICT DO UPDATE SET
+                       value = excluded.value,
+                       server_modified = excluded.server_modified",
+                    rusqlite::named_params! {
+                        ":name": &self.name,
+                        ":key": &record.key,
+                        ":value": &record.value,
+                        ":server_modified": record.server_modified,
+                    },
+                )?;
+
+                max_server_modified = Some(max_server_modified.map_or(
+                    record.server_modified,
+                    |max: i64| max.max(record.server_modified),
+                ));
+
+                if resolved != remote_value {
+                    outgoing.push(OutgoingRecord {
+                        key: record.key.clone(),
+                        value: resolved,
+                    });
+                }
+            }
+
+            if let Some(server_modified) = max_server_modified {
+                tx.execute(
+                    "UPDATE sync_meta
+                     SET last_sync = MAX(last_sync, :last_sync)
+                     WHERE db_id = (SELECT id FROM dbs WHERE name = :name)",
+                    rusqlite::named_params! {
+                        ":name": &self.name,
+                        ":last_sync": server_modified,
+                    },
+                )?;
+            }
+
+            let puts = resolved_writes
+                .iter()
+                .filter_map(|(key, value)| value.as_ref().map(|value| Put(key, value)))
+                .collect::<Vec<_>>();
+            let deletes = resolved_writes
+                .iter()
+                .filter(|(_, value)| value.is_none())
+                .map(|(key, _)| Delete(key))
+                .collect::<Vec<_>>();
+            let changes = self.put_or_delete_tx(tx, &puts, &deletes, None)?;
+
+            Ok((Outgoing { records: outgoing }, changes))
+        })?;
+
+        self.store.notify(&changes);
+        Ok(outgoing)
+    }
+
+    fn ensure_sync_meta_row(&self, tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+        tx.execute(
+            "INSERT OR IGNORE INTO dbs(name) VALUES(:name)",
+            rusqlite::named_params! { ":name": &self.name },
+        )?;
+        tx.execute(
+            "INSERT OR IGNORE INTO sync_meta(db_id) VALUES((SELECT id FROM dbs WHERE name = :name))",
+            rusqlite::named_params! { ":name": &self.name },
+        )?;
+        Ok(())
+    }
+
+    /// Deletes every key in this database, going through
+    /// [`Database::delete_chunk`] (the same path [`Database::put_or_delete`]
+    /// uses) so each removed key is tombstoned, exactly like an individual
+    /// [`Database::delete`]; this keeps bulk deletes visible to
+    /// [`Database::changes_since`]/[`Database::apply_incoming`], instead
+    /// of just disappearing from `data`.
+    ///
+    /// Leaves this database's `dbs` row (and its sync state in
+    /// `sync_meta`/`mirror`) untouched, so a later sync still has
+    /// somewhere to record these deletions.
+    pub fn clear(&self) -> Result<(), DatabaseError> {
+        let writer = self.store.writer()?;
+        let changes = writer.write(|tx| {
+            let mut statement = tx.prepare_cached(
+                "SELECT key, json(value) AS value
+                 FROM data
+                 JOIN dbs ON dbs.id = data.db_id
+                 WHERE dbs.name = :name",
+            )?;
+            let rows = statement
+                .query_map(rusqlite::named_params! { ":name": self.name }, |row| {
+                    Ok((row.get::<_, Key>("key")?, row.get::<_, Value>("value")?))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            let keys = rows.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>();
+            let deletes = keys.iter().map(Delete).collect::<Vec<_>>();
+            sql::each_chunk(&deletes, Self::DELETE_COLUMNS_PER_ROW, |chunk| {
+                self.delete_chunk(tx, chunk)
+            })?;
+
+            Ok(rows
+                .into_iter()
+                .map(|(key, value)| ChangeRecord {
+                    db_name: self.name.to_string(),
+                    key,
+                    old_value: Some(value),
+                    new_value: None,
+                })
+                .collect())
+        })?;
+        self.store.notify(&changes);
+        Ok(())
+    }
+
+    /// Runs `f` with a [`Recorder`] that captures every `put`/`delete` it
+    /// makes as a changeset, using SQLite's session extension (the
+    /// `session` module in the vendored `rusqlite`) attached to the
+    /// `data` table, then returns the accumulated changeset serialized
+    /// to bytes.
+    ///
+    /// `f` must make its writes through the `Recorder` it's given, not
+    /// through `self` or another `Database` over the same store: this
+    /// method holds the store's writer lock for the whole recording, and
+    /// that lock isn't reentrant. The session is attached to the whole
+    /// `data` table, shared by every named database in the store, so a
+    /// recording that writes to more than one named database captures
+    /// all of their changes together.
+    pub fn record(
+        &self,
+        f: impl FnOnce(&Recorder<'_, 'a>) -> Result<(), DatabaseError>,
+    ) -> Result<Vec<u8>, DatabaseError> {
+        let writer = self.store.writer()?;
+        let mut session = rusqlite::session::Session::new(&writer.0)?;
+        session.attach(Some("data"))?;
+
+        let recorder = Recorder {
+            database: self,
+            writer: &writer,
+        };
+        f(&recorder)?;
+
+        let mut changeset = Vec::new();
+        session.changeset_strm(&mut changeset)?;
+        Ok(changeset)
+    }
+
+    /// Replays a changeset captured by [`Database::record`] (from this
+    /// store or another one) through [`Database::put_or_delete_tx`],
+    /// rather than applying it to `data` directly: that's the only way a
+    /// replayed deletion gets tombstoned, a replayed write gets a fresh
+    /// `local_change_counter` stamped from this store's own counter
+    /// (instead of replaying whatever counter the origin store happened
+    /// to have), and observers are notified.
+    ///
+    /// A row the changeset expects to find unmodified, but that has
+    /// since changed locally, is resolved according to `conflict`.
+    pub fn apply_changeset(
+        &self,
+        changeset: &[u8],
+        conflict: ConflictPolicy,
+    ) -> Result<(), DatabaseError> {
+        let writer = self.store.writer()?;
+        let changes = writer.write(|tx| {
+            let mut input = &mut &*changeset;
+            let mut iter = rusqlite::session::ChangesetIter::start_strm(&mut input)?;
+
+            let mut resolved_writes: Vec<(Key, Option<Value>)> = Vec::new();
+            while let Some(item) = iter.next()? {
+                let key = match item.new_value(1).or_else(|| item.old_value(1)) {
+                    Some(value) => Key::column_result(value).map_err(|err| {
+                        rusqlite::Error::FromSqlConversionFailure(
+                            1,
+                            rusqlite::types::Type::Text,
+                            Box::new(err),
+                        )
+                    })?,
+                    // Neither the new nor the old row has a key; not a
+                    // change to the `data` table.
+                    None => continue,
+                };
+
+                let expected = match item.old_value(2) {
+                    Some(value) => Some(Self::decode_jsonb_tx(tx, value)?),
+                    None => None,
+                };
+                let new_value = match item.new_value(2) {
+                    Some(value) => Some(Self::decode_jsonb_tx(tx, value)?),
+                    None => None,
+                };
+
+                if self.current_value_tx(tx, &key)? != expected {
+                    match conflict {
+                        ConflictPolicy::Abort => return Err(DatabaseError::ChangesetConflict { key }),
+                        ConflictPolicy::Skip => continue,
+                        ConflictPolicy::Replace => {}
+                    }
+                }
+
+                resolved_writes.push((key, new_value));
+            }
+
+            let puts = resolved_writes
+                .iter()
+                .filter_map(|(key, value)| value.as_ref().map(|value| Put(key, value)))
+                .collect::<Vec<_>>();
+            let deletes = resolved_writes
+                .iter()
+                .filter(|(_, value)| value.is_none())
+                .map(|(key, _)| Delete(key))
+                .collect::<Vec<_>>();
+            self.put_or_delete_tx(tx, &puts, &deletes, None)
+        })?;
+        self.store.notify(&changes);
+        Ok(())
+    }
+
+    /// Looks up the current value (if any) for `key` in this database,
+    /// within an already-open transaction. Shared by
+    /// [`Database::compare_and_swap`] and [`Database::apply_changeset`],
+    /// which both need to compare a key's current value against an
+    /// expected one before deciding whether to write.
+    fn current_value_tx(
+        &self,
+        tx: &rusqlite::Transaction<'_>,
+        key: &Key,
+    ) -> rusqlite::Result<Option<Value>> {
+        let mut statement = tx.prepare_cached(
+            "SELECT
+               json(v.value) AS value
+             FROM
+               data v
+             JOIN
+               dbs d
+               ON d.id = v.db_id
+             WHERE
+               d.name = :name
+               AND v.key = :key
+            ",
+        )?;
+        let mut rows = statement.query(rusqlite::named_params! {
+            ":name": &self.name,
+            ":key": key,
+        })?;
+        match rows.next()? {
+            Some(row) => Ok(Some(row.get::<_, Value>("value")?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Converts a `data.value` column's raw `JSONB` bytes, as read out of
+    /// a changeset by [`Database::apply_changeset`], back into a `Value`.
+    ///
+    /// The bytes a changeset carries for this column are SQLite's
+    /// internal `JSONB` encoding (the same bytes stored on disk), not
+    /// `JSON` text, so they can't go through [`Value`]'s `FromSql` impl
+    /// directly; only SQLite's own `json()` function can decode them.
+    fn decode_jsonb_tx(
+        tx: &rusqlite::Transaction<'_>,
+        value: ValueRef<'_>,
+    ) -> rusqlite::Result<Value> {
+        tx.query_row("SELECT json(?1) AS value", [value], |row| row.get("value"))
+    }
+
+    /// Materializes every pair in `range` matching `options` into a
+    /// `Vec`. Prefer [`Database::for_each`] for large ranges, since this
+    /// reads every matching row into memory before returning.
     pub fn enumerate(
         &self,
         range: impl RangeBounds<Key>,
-        options: &GetOptions,
+        options: &EnumerateOptions,
     ) -> Result<Vec<(Key, Value)>, DatabaseError> {
+        let mut pairs = Vec::new();
+        self.for_each(range, options, |key, value| {
+            pairs.push((key, value));
+            ControlFlow::Continue(())
+        })?;
+        Ok(pairs)
+    }
+
+    /// Fetches one bounded page of `range` matching `options`, plus the
+    /// continuation key to pass as `options.start_after(...)` to fetch
+    /// the next page.
+    ///
+    /// `options.limit` is the page size; without a limit, this returns
+    /// everything in one page (`next` is always `None` in that case).
+    /// Keyset-paginates rather than using `OFFSET`, so pages stay cheap
+    /// to fetch no matter how far into the range they start.
+    pub fn paginate(
+        &self,
+        range: impl RangeBounds<Key>,
+        options: &EnumerateOptions,
+    ) -> Result<Page, DatabaseError> {
+        let page_size = options.limit;
+        let mut probe = options.clone();
+        if let Some(limit) = page_size {
+            probe.limit(limit.saturating_add(1));
+        }
+
+        let mut pairs = Vec::new();
+        self.for_each(range, &probe, |key, value| {
+            pairs.push((key, value));
+            ControlFlow::Continue(())
+        })?;
+
+        let next = match page_size {
+            Some(limit) if pairs.len() > limit as usize => {
+                pairs.truncate(limit as usize);
+                pairs.last().map(|(key, _)| key.clone())
+            }
+            _ => None,
+        };
+
+        Ok(Page { pairs, next })
+    }
+
+    /// Streams every pair in `range` matching `options` to `f`, without
+    /// materializing the whole result set in memory.
+    ///
+    /// `f` is called once per pair, in key order (descending, if
+    /// `options.reverse` is set); returning [`ControlFlow::Break`] stops
+    /// the scan early, closing the underlying SQLite cursor.
+    pub fn for_each(
+        &self,
+        range: impl RangeBounds<Key>,
+        options: &EnumerateOptions,
+        mut f: impl FnMut(Key, Value) -> ControlFlow<()>,
+    ) -> Result<(), DatabaseError> {
         let reader = match options.concurrent {
             true => self.store.reader()?,
             false => self.store.writer()?,
         };
         reader.read(|conn| {
-            let fragment = RangeFragment::new("v.key", &range);
+            let range = RangeFragment::new("v.key", &range);
+            let prefix = PrefixFragment::new("v.key", options.prefix.as_ref());
+            let order = if options.reverse { "DESC" } else { "ASC" };
+            let start_after = match (&options.start_after, options.reverse) {
+                (Some(_), false) => "v.key > :start_after",
+                (Some(_), true) => "v.key < :start_after",
+                (None, _) => "1",
+            };
+            let limit = match options.limit {
+                Some(limit) => format!("LIMIT {limit}"),
+                None => String::new(),
+            };
             let mut statement = conn.prepare_cached(&format!(
                 "SELECT
                    v.key,
@@ -101,30 +997,33 @@ impl<'a> Database<'a> {
                     ON d.id = v.db_id
                   WHERE
                     d.name = :name
-                    AND {fragment}
+                    AND {range}
+                    AND {prefix}
+                    AND {start_after}
                   ORDER BY
-                    v.key ASC
+                    v.key {order}
+                  {limit}
                 ",
             ))?;
-            let params = match (fragment.start_param(), fragment.end_param()) {
-                // A bounded range binds parameters for the database name
-                // and both key bounds.
-                (Some(p), Some(q)) => vec![(":name", &self.name as &dyn ToSql), p, q],
-                // A half-bounded range binds parameters for the database name
-                // and only key bound.
-                (Some(p), None) | (None, Some(p)) => vec![(":name", &self.name as &dyn ToSql), p],
-                // An unbounded range only binds the database name.
-                (None, None) => vec![(":name", &self.name as &dyn ToSql)],
-            };
-            let values = statement
-                .query(params.as_slice())?
-                .mapped(|row| {
-                    let key = row.get::<_, Key>("key")?;
-                    let value = row.get::<_, Value>("value")?;
-                    Ok((key, value))
-                })
-                .collect::<rusqlite::Result<Vec<_>>>()?;
-            Ok(values)
+            let mut params: Vec<(&str, &dyn ToSql)> = vec![(":name", &self.name as &dyn ToSql)];
+            params.extend(range.start_param());
+            params.extend(range.end_param());
+            params.extend(prefix.param());
+            params.extend(
+                options
+                    .start_after
+                    .as_ref()
+                    .map(|key| (":start_after", key as &dyn ToSql)),
+            );
+            let mut rows = statement.query(params.as_slice())?;
+            while let Some(row) = rows.next()? {
+                let key = row.get::<_, Key>("key")?;
+                let value = row.get::<_, Value>("value")?;
+                if f(key, value).is_break() {
+                    break;
+                }
+            }
+            Ok(())
         })
     }
 
@@ -162,56 +1061,472 @@ impl<'a> Database<'a> {
         })
     }
 
-    fn put_or_delete(&self, puts: &[Put], deletes: &[Delete]) -> Result<(), DatabaseError> {
+    /// Number of parameters [`Database::put_chunk`] binds per row, not
+    /// counting the single shared `:name` parameter.
+    const PUT_COLUMNS_PER_ROW: usize = 3;
+
+    /// Number of parameters [`Database::delete_chunk`] binds per row, not
+    /// counting the single shared `:name` parameter. Sized for the
+    /// two-column tombstone upsert it may issue, so that chunk doesn't
+    /// itself need to be split further.
+    const DELETE_COLUMNS_PER_ROW: usize = 2;
+
+    fn put_or_delete(
+        &self,
+        puts: &[Put],
+        deletes: &[Delete],
+        quota: Option<&QuotaOptions>,
+    ) -> Result<(), DatabaseError> {
         let writer = self.store.writer()?;
-        writer.write(|tx| {
-            if !puts.is_empty() {
-                let mut statement =
-                    tx.prepare_cached("INSERT OR IGNORE INTO dbs(name) VALUES(:name)")?;
-                statement.execute(rusqlite::named_params! {
-                    ":name": &self.name,
-                })?;
+        let changes = writer.write(|tx| self.put_or_delete_tx(tx, puts, deletes, quota))?;
+        self.store.notify(&changes);
+        Ok(())
+    }
+
+    /// The transactional body of [`Database::put_or_delete`], split out
+    /// so [`Database::record`] can run it against a transaction on a
+    /// writer connection it's already holding for a session recording,
+    /// instead of acquiring (and deadlocking on) a second writer lock.
+    fn put_or_delete_tx(
+        &self,
+        tx: &rusqlite::Transaction<'_>,
+        puts: &[Put],
+        deletes: &[Delete],
+        quota: Option<&QuotaOptions>,
+    ) -> Result<Vec<ChangeRecord>, DatabaseError> {
+        if !puts.is_empty() {
+            let mut statement =
+                tx.prepare_cached("INSERT OR IGNORE INTO dbs(name) VALUES(:name)")?;
+            statement.execute(rusqlite::named_params! {
+                ":name": &self.name,
+            })?;
+        }
+
+        let old_values = self.old_values(tx, puts, deletes)?;
+        if let Some(quota) = quota {
+            self.check_quota(tx, puts, deletes, &old_values, quota)?;
+        }
+        let changes = self.diff_changes(puts, deletes, &old_values);
+
+        sql::each_chunk(puts, Self::PUT_COLUMNS_PER_ROW, |chunk| {
+            self.put_chunk(tx, chunk)
+        })?;
+        sql::each_chunk(deletes, Self::DELETE_COLUMNS_PER_ROW, |chunk| {
+            self.delete_chunk(tx, chunk)
+        })?;
+
+        Ok(changes)
+    }
+
+    /// Looks up the current value (if any) for every key in `puts` and
+    /// `deletes`. Used both to diff changes for observers and to project
+    /// the quota impact of a write, before either is applied.
+    ///
+    /// Must run before `puts`/`deletes` are applied, so the looked-up
+    /// values reflect the pre-write state.
+    fn old_values(
+        &self,
+        tx: &rusqlite::Transaction<'_>,
+        puts: &[Put],
+        deletes: &[Delete],
+    ) -> rusqlite::Result<HashMap<Key, Value>> {
+        let keys = puts
+            .iter()
+            .map(|put| put.0)
+            .chain(deletes.iter().map(|delete| delete.0))
+            .collect::<Vec<_>>();
+
+        let mut old_values = HashMap::new();
+        sql::each_chunk(&keys, 1, |chunk| {
+            let placeholders = (0..chunk.len())
+                .map(|i| format!(":key{i}"))
+                .collect::<Vec<_>>();
+            let mut statement = tx.prepare_cached(&format!(
+                "SELECT key, json(value) AS value
+                 FROM data
+                 WHERE
+                   db_id = (SELECT id FROM dbs WHERE name = :name)
+                   AND key IN ({})",
+                placeholders.join(", "),
+            ))?;
+            let mut params: Vec<(&str, &dyn ToSql)> = Vec::with_capacity(chunk.len() + 1);
+            params.push((":name", &self.name as &dyn ToSql));
+            for (name, key) in placeholders.iter().zip(chunk) {
+                params.push((name.as_str(), *key as &dyn ToSql));
+            }
+            let mut rows = statement.query(params.as_slice())?;
+            while let Some(row) = rows.next()? {
+                old_values.insert(row.get::<_, Key>("key")?, row.get::<_, Value>("value")?);
             }
+            Ok(())
+        })?;
 
-            for Put(key, value) in puts {
-                let mut statement = tx.prepare_cached(
-                    "INSERT INTO data(
-                       db_id,
-                       key,
-                       value
-                     )
-                     VALUES(
-                       (SELECT id FROM dbs WHERE name = :name),
-                       :key,
-                       jsonb(:value)
-                     )
-                     ON CONFLICT DO UPDATE SET
-                       value = excluded.value",
-                )?;
-                statement.execute(rusqlite::named_params! {
-                    ":name": &self.name,
-                    ":key": key,
-                    ":value": value,
-                })?;
+        Ok(old_values)
+    }
+
+    /// Diffs `puts`/`deletes` against `old_values`, suppressing no-op
+    /// writes where the old and new values are equal, to build the batch
+    /// of [`ChangeRecord`]s for observers.
+    fn diff_changes(
+        &self,
+        puts: &[Put],
+        deletes: &[Delete],
+        old_values: &HashMap<Key, Value>,
+    ) -> Vec<ChangeRecord> {
+        let mut changes = Vec::new();
+        for put in puts {
+            let old_value = old_values.get(put.0).cloned();
+            let new_value = Some(put.1.clone());
+            if old_value != new_value {
+                changes.push(ChangeRecord {
+                    db_name: self.name.to_string(),
+                    key: put.0.clone(),
+                    old_value,
+                    new_value,
+                });
             }
+        }
+        for delete in deletes {
+            if let Some(old_value) = old_values.get(delete.0).cloned() {
+                changes.push(ChangeRecord {
+                    db_name: self.name.to_string(),
+                    key: delete.0.clone(),
+                    old_value: Some(old_value),
+                    new_value: None,
+                });
+            }
+        }
+        changes
+    }
 
-            for Delete(key) in deletes {
-                let mut statement = tx.prepare_cached(
-                    "DELETE FROM data
-                     WHERE
-                       db_id = (SELECT id FROM dbs WHERE name = :name)
-                       AND key = :key
-                    ",
-                )?;
-                statement.execute(rusqlite::named_params! {
-                    ":name": &self.name,
-                    ":key": key,
-                })?;
+    /// Checks that applying `puts`/`deletes` wouldn't exceed `quota`,
+    /// given the pre-write state in `old_values`.
+    fn check_quota(
+        &self,
+        tx: &rusqlite::Transaction<'_>,
+        puts: &[Put],
+        deletes: &[Delete],
+        old_values: &HashMap<Key, Value>,
+        quota: &QuotaOptions,
+    ) -> Result<(), DatabaseError> {
+        for put in puts {
+            let attempted = Self::entry_bytes(put.0, put.1);
+            if attempted > quota.max_item_bytes {
+                return Err(DatabaseError::QuotaExceeded {
+                    limit: quota.max_item_bytes,
+                    attempted,
+                });
+            }
+        }
+
+        let mut bytes_delta = 0i64;
+        let mut item_delta = 0i64;
+        for put in puts {
+            let new_bytes = Self::entry_bytes(put.0, put.1) as i64;
+            match old_values.get(put.0) {
+                Some(old) => bytes_delta += new_bytes - Self::entry_bytes(put.0, old) as i64,
+                None => {
+                    bytes_delta += new_bytes;
+                    item_delta += 1;
+                }
+            }
+        }
+        for delete in deletes {
+            if let Some(old) = old_values.get(delete.0) {
+                bytes_delta -= Self::entry_bytes(delete.0, old) as i64;
+                item_delta -= 1;
             }
+        }
+
+        // Computed from `key`/`value` in Rust (via `entry_bytes`, the same
+        // helper `bytes_in_use` uses), rather than with SQL `LENGTH()`:
+        // `key`/`json(value)` are `TEXT`, and `LENGTH()` on `TEXT` counts
+        // characters, not bytes, which undercounts multi-byte UTF-8.
+        let (current_bytes, current_items) = {
+            let mut statement = tx.prepare_cached(
+                "SELECT key, json(value) AS value
+                 FROM data
+                 WHERE db_id = (SELECT id FROM dbs WHERE name = :name)",
+            )?;
+            let mut rows =
+                statement.query(rusqlite::named_params! { ":name": &self.name })?;
+            let mut bytes = 0i64;
+            let mut items = 0i64;
+            while let Some(row) = rows.next()? {
+                let key = row.get::<_, Key>("key")?;
+                let value = row.get::<_, Value>("value")?;
+                bytes += Self::entry_bytes(&key, &value) as i64;
+                items += 1;
+            }
+            (bytes, items)
+        };
+
+        let attempted_bytes = (current_bytes + bytes_delta).max(0) as u64;
+        if attempted_bytes > quota.max_total_bytes {
+            return Err(DatabaseError::QuotaExceeded {
+                limit: quota.max_total_bytes,
+                attempted: attempted_bytes,
+            });
+        }
+
+        let attempted_items = (current_items + item_delta).max(0) as u64;
+        if attempted_items > quota.max_items {
+            return Err(DatabaseError::QuotaExceeded {
+                limit: quota.max_items,
+                attempted: attempted_items,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Upserts a chunk of puts as a single multi-row `INSERT`, then
+    /// clears any tombstones for the keys just written.
+    ///
+    /// `chunk.len()` is bounded so that the `INSERT`'s bound parameters
+    /// (`Self::PUT_COLUMNS_PER_ROW` per row, plus one shared `:name`)
+    /// stay within SQLite's limit.
+    fn put_chunk(&self, tx: &rusqlite::Transaction<'_>, chunk: &[Put]) -> rusqlite::Result<()> {
+        let start = schema::reserve_local_change_counters(tx, chunk.len() as i64)?;
+        let counters = (start..start + chunk.len() as i64).collect::<Vec<_>>();
+
+        let rows = (0..chunk.len())
+            .map(|i| {
+                format!(
+                    "((SELECT id FROM dbs WHERE name = :name), :key{i}, jsonb(:value{i}), :counter{i})"
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut statement = tx.prepare_cached(&format!(
+            "INSERT INTO data(db_id, key, value, local_change_counter)
+             VALUES {rows}
+             ON CONFLICT DO UPDATE SET
+               value = excluded.value,
+               local_change_counter = excluded.local_change_counter"
+        ))?;
 
+        let names = (0..chunk.len())
+            .map(|i| (format!(":key{i}"), format!(":value{i}"), format!(":counter{i}")))
+            .collect::<Vec<_>>();
+        let mut params: Vec<(&str, &dyn ToSql)> = Vec::with_capacity(chunk.len() * 3 + 1);
+        params.push((":name", &self.name as &dyn ToSql));
+        for (i, put) in chunk.iter().enumerate() {
+            let (key_name, value_name, counter_name) = &names[i];
+            params.push((key_name.as_str(), put.0 as &dyn ToSql));
+            params.push((value_name.as_str(), put.1 as &dyn ToSql));
+            params.push((counter_name.as_str(), &counters[i] as &dyn ToSql));
+        }
+        statement.execute(params.as_slice())?;
+
+        let keys = chunk.iter().map(|put| put.0).collect::<Vec<_>>();
+        sql::each_chunk(&keys, 1, |chunk| {
+            let placeholders = (0..chunk.len())
+                .map(|i| format!(":key{i}"))
+                .collect::<Vec<_>>();
+            let mut statement = tx.prepare_cached(&format!(
+                "DELETE FROM tombstones
+                 WHERE
+                   db_id = (SELECT id FROM dbs WHERE name = :name)
+                   AND key IN ({})",
+                placeholders.join(", "),
+            ))?;
+            let mut params: Vec<(&str, &dyn ToSql)> = Vec::with_capacity(chunk.len() + 1);
+            params.push((":name", &self.name as &dyn ToSql));
+            for (name, key) in placeholders.iter().zip(chunk) {
+                params.push((name.as_str(), *key as &dyn ToSql));
+            }
+            statement.execute(params.as_slice())?;
             Ok(())
         })
     }
+
+    /// Deletes a chunk of keys as a single multi-row `DELETE`, then
+    /// records a tombstone for each key that was actually present.
+    ///
+    /// `chunk.len()` is bounded so that the tombstone upsert's bound
+    /// parameters (`Self::DELETE_COLUMNS_PER_ROW` per row, plus one
+    /// shared `:name`) stay within SQLite's limit.
+    fn delete_chunk(&self, tx: &rusqlite::Transaction<'_>, chunk: &[Delete]) -> rusqlite::Result<()> {
+        let keys = chunk.iter().map(|delete| delete.0).collect::<Vec<_>>();
+        let placeholders = (0..keys.len())
+            .map(|i| format!(":key{i}"))
+            .collect::<Vec<_>>();
+        let mut statement = tx.prepare_cached(&format!(
+            "DELETE FROM data
+             WHERE
+               db_id = (SELECT id FROM dbs WHERE name = :name)
+               AND key IN ({})
+             RETURNING key",
+            placeholders.join(", "),
+        ))?;
+        let mut params: Vec<(&str, &dyn ToSql)> = Vec::with_capacity(keys.len() + 1);
+        params.push((":name", &self.name as &dyn ToSql));
+        for (name, key) in placeholders.iter().zip(&keys) {
+            params.push((name.as_str(), *key as &dyn ToSql));
+        }
+        let deleted = statement
+            .query(params.as_slice())?
+            .mapped(|row| row.get::<_, Key>("key"))
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        if deleted.is_empty() {
+            return Ok(());
+        }
+
+        let start = schema::reserve_local_change_counters(tx, deleted.len() as i64)?;
+        let counters = (start..start + deleted.len() as i64).collect::<Vec<_>>();
+
+        let rows = (0..deleted.len())
+            .map(|i| format!("((SELECT id FROM dbs WHERE name = :name), :key{i}, :counter{i})"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut statement = tx.prepare_cached(&format!(
+            "INSERT INTO tombstones(db_id, key, local_change_counter)
+             VALUES {rows}
+             ON CONFLICT DO UPDATE SET
+               local_change_counter = excluded.local_change_counter"
+        ))?;
+        let names = (0..deleted.len())
+            .map(|i| (format!(":key{i}"), format!(":counter{i}")))
+            .collect::<Vec<_>>();
+        let mut params: Vec<(&str, &dyn ToSql)> = Vec::with_capacity(deleted.len() * 2 + 1);
+        params.push((":name", &self.name as &dyn ToSql));
+        for (i, key) in deleted.iter().enumerate() {
+            let (key_name, counter_name) = &names[i];
+            params.push((key_name.as_str(), key as &dyn ToSql));
+            params.push((counter_name.as_str(), &counters[i] as &dyn ToSql));
+        }
+        statement.execute(params.as_slice())?;
+
+        Ok(())
+    }
+}
+
+/// A handle passed to the closure given to [`Database::record`].
+///
+/// Offers the same `put`/`delete` operations as [`Database`], but runs
+/// them against the writer connection [`Database::record`] is already
+/// holding for its session recording, instead of acquiring a second
+/// writer lock (which would deadlock).
+pub struct Recorder<'r, 'a> {
+    database: &'r Database<'a>,
+    writer: &'r Writer<'a>,
+}
+
+impl<'r, 'a> Recorder<'r, 'a> {
+    pub fn put<K, V>(&self, pairs: &[(K, Option<V>)], quota: &QuotaOptions) -> Result<(), DatabaseError>
+    where
+        K: Borrow<Key>,
+        V: Borrow<Value>,
+    {
+        let (updates, deletions) = Database::partition_pairs(pairs);
+        self.put_or_delete(&updates, &deletions, Some(quota))
+    }
+
+    pub fn delete(&self, key: &Key) -> Result<(), DatabaseError> {
+        self.put_or_delete(&[], &[Delete(key)], None)
+    }
+
+    fn put_or_delete(
+        &self,
+        puts: &[Put],
+        deletes: &[Delete],
+        quota: Option<&QuotaOptions>,
+    ) -> Result<(), DatabaseError> {
+        let changes = self
+            .writer
+            .write(|tx| self.database.put_or_delete_tx(tx, puts, deletes, quota))?;
+        self.database.store.notify(&changes);
+        Ok(())
+    }
+}
+
+/// What to do when replaying a changeset via [`Database::apply_changeset`]
+/// hits a row that's changed since the changeset was recorded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConflictPolicy {
+    /// Abort the whole apply, rolling back any rows already changed.
+    Abort,
+    /// Overwrite the conflicting row with the changeset's version.
+    Replace,
+    /// Leave the conflicting row as-is and continue with the rest of the
+    /// changeset.
+    Skip,
+}
+
+/// A record fetched from the server, to be merged into local data by
+/// [`Database::apply_incoming`] or [`Database::sync`]. `value` is `None`
+/// for a server-side deletion.
+#[derive(Clone, Debug)]
+pub struct IncomingRecord {
+    pub key: Key,
+    pub value: Option<Value>,
+    pub server_modified: i64,
+}
+
+/// A local change to upload to the server, as returned by
+/// [`Database::apply_incoming`] or [`Database::sync`]. `value` is `None`
+/// for a local deletion.
+#[derive(Clone, Debug)]
+pub struct OutgoingRecord {
+    pub key: Key,
+    pub value: Option<Value>,
+}
+
+/// The result of a [`Database::sync`] round: the records that still need
+/// to be uploaded to the server.
+#[derive(Clone, Debug, Default)]
+pub struct Outgoing {
+    pub records: Vec<OutgoingRecord>,
+}
+
+/// A caller-supplied conflict-resolution policy for [`Database::sync`].
+///
+/// Only consulted when both the local and remote values for a key
+/// changed since the last sync, and changed to different things; the
+/// trivial cases (only one side changed, or both changed to the same
+/// value) are resolved by `sync` itself.
+pub trait Reconciler {
+    /// Resolves a conflict for `key`. `base` is the value as of the last
+    /// successful sync, `local` is the current local value, and `remote`
+    /// is the incoming server value. Returns the value to keep in
+    /// `data` (`None` to delete).
+    fn reconcile(
+        &mut self,
+        key: &Key,
+        base: Option<&Value>,
+        local: Option<&Value>,
+        remote: Option<&Value>,
+    ) -> Option<Value>;
+}
+
+/// The default [`Reconciler`]: the server's value always wins.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RemoteWins;
+
+impl Reconciler for RemoteWins {
+    fn reconcile(
+        &mut self,
+        _key: &Key,
+        _base: Option<&Value>,
+        _local: Option<&Value>,
+        remote: Option<&Value>,
+    ) -> Option<Value> {
+        remote.cloned()
+    }
+}
+
+/// Size and count statistics for a database's underlying store.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Stats {
+    /// The number of key/value pairs in this named database.
+    pub pair_count: u64,
+    /// The on-disk size, in bytes, of the store's physical SQLite file.
+    pub file_size: u64,
+    /// The on-disk size, in bytes, of the store's `-wal` file.
+    pub wal_size: u64,
+    /// The store's SQLite page size, in bytes.
+    pub page_size: u64,
 }
 
 /// Options for reading keys and values.
@@ -241,10 +1556,261 @@ impl Default for GetOptions {
     }
 }
 
+/// Options for enumerating keys and values.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct EnumerateOptions {
+    concurrent: bool,
+    prefix: Option<Key>,
+    limit: Option<u32>,
+    reverse: bool,
+    start_after: Option<Key>,
+}
+
+impl EnumerateOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the option for concurrent reads; see [`GetOptions::concurrent`].
+    pub fn concurrent(&mut self, concurrent: bool) -> &mut Self {
+        self.concurrent = concurrent;
+        self
+    }
+
+    /// Restricts the enumeration to keys starting with `prefix`, in
+    /// addition to the range passed to `enumerate`/`for_each`.
+    pub fn prefix(&mut self, prefix: Key) -> &mut Self {
+        self.prefix = Some(prefix);
+        self
+    }
+
+    /// Caps the number of pairs returned.
+    pub fn limit(&mut self, limit: u32) -> &mut Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Walks keys in descending, rather than ascending, order.
+    pub fn reverse(&mut self, reverse: bool) -> &mut Self {
+        self.reverse = reverse;
+        self
+    }
+
+    /// Resumes the enumeration strictly after `key`, in scan order, as a
+    /// continuation token for keyset pagination; see [`Database::paginate`].
+    pub fn start_after(&mut self, key: Key) -> &mut Self {
+        self.start_after = Some(key);
+        self
+    }
+}
+
+/// A single page of results from [`Database::paginate`].
+///
+/// `next`, when present, is the continuation key to pass to
+/// [`EnumerateOptions::start_after`] to fetch the page after this one.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Page {
+    pub pairs: Vec<(Key, Value)>,
+    pub next: Option<Key>,
+}
+
+/// Storage limits enforced by [`Database::put`], checked against the
+/// projected state of the database before a write commits.
+///
+/// Mirrors the quota the webext_storage component enforces for
+/// `storage.sync`: the defaults are the same 8192 bytes per item,
+/// ~102400 bytes total per database, and a cap on the number of items.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct QuotaOptions {
+    max_item_bytes: u64,
+    max_total_bytes: u64,
+    max_items: u64,
+}
+
+impl QuotaOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum size, in bytes, of a single key/value pair.
+    pub fn max_item_bytes(&mut self, max_item_bytes: u64) -> &mut Self {
+        self.max_item_bytes = max_item_bytes;
+        self
+    }
+
+    /// Sets the maximum total size, in bytes, of all pairs in the
+    /// database.
+    pub fn max_total_bytes(&mut self, max_total_bytes: u64) -> &mut Self {
+        self.max_total_bytes = max_total_bytes;
+        self
+    }
+
+    /// Sets the maximum number of pairs in the database.
+    pub fn max_items(&mut self, max_items: u64) -> &mut Self {
+        self.max_items = max_items;
+        self
+    }
+}
+
+impl Default for QuotaOptions {
+    fn default() -> Self {
+        Self {
+            max_item_bytes: 8192,
+            max_total_bytes: 102_400,
+            max_items: 512,
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum DatabaseError {
     #[error("store: {0}")]
     Store(#[from] StoreError),
     #[error("sqlite: {0}")]
     Sqlite(#[from] rusqlite::Error),
+    #[error("quota exceeded: attempted {attempted}, limit {limit}")]
+    QuotaExceeded { limit: u64, attempted: u64 },
+    #[error("value: {0}")]
+    Value(#[from] ValueError),
+    #[error("changeset conflict on key {key:?}")]
+    ChangesetConflict { key: Key },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::skv::store::StorePath;
+
+    fn open() -> Store {
+        Store::open(&StorePath::for_in_memory()).expect("failed to open in-memory store")
+    }
+
+    /// A multi-row [`Database::put`] must stamp every row with its own
+    /// `local_change_counter`, not the same one reserved for the whole
+    /// chunk.
+    #[test]
+    fn put_chunk_reserves_distinct_counters() {
+        let store = open();
+        let db = Database::new(&store, "test");
+
+        let pairs = (0..5)
+            .map(|i| (Key::from(format!("k{i}")), Some(Value::from(serde_json::json!(i)))))
+            .collect::<Vec<_>>();
+        db.put(&pairs, &QuotaOptions::new()).expect("put failed");
+
+        let changes = db.changes_since(0).expect("changes_since failed");
+        assert_eq!(changes.len(), 5);
+        let mut counters = changes.iter().map(|(counter, _)| *counter).collect::<Vec<_>>();
+        counters.sort_unstable();
+        counters.dedup();
+        assert_eq!(counters.len(), 5, "every row must get a distinct counter");
+    }
+
+    /// [`Database::check_quota`]'s running total must be computed from
+    /// byte lengths, not SQL `LENGTH()` (which counts `TEXT` characters),
+    /// or multi-byte UTF-8 values silently evade `max_total_bytes`.
+    #[test]
+    fn quota_counts_bytes_not_chars() {
+        let store = open();
+        let db = Database::new(&store, "test");
+
+        // 50 two-byte characters: 52 chars but 102 bytes once quoted as
+        // JSON, so a char-counting bug and a byte-counting fix disagree
+        // about how much room is left for the next put.
+        let wide = "é".repeat(50);
+        let mut loose = QuotaOptions::new();
+        loose.max_total_bytes(1_000);
+        db.put(
+            &[(Key::from("k1".to_string()), Some(Value::from(serde_json::json!(wide))))],
+            &loose,
+        )
+        .expect("initial put failed");
+
+        let mut tight = QuotaOptions::new();
+        tight.max_total_bytes(106);
+        let err = db
+            .put(
+                &[(Key::from("k2".to_string()), Some(Value::from(serde_json::json!(1))))],
+                &tight,
+            )
+            .expect_err("byte-accurate accounting must reject this put");
+        assert!(matches!(err, DatabaseError::QuotaExceeded { .. }));
+    }
+
+    /// [`Database::apply_incoming`] must three-way merge staged records
+    /// via [`Database::sync`], not blindly overwrite local data: a key
+    /// the server hasn't touched since the last sync keeps its local
+    /// value, while a genuine conflict still falls back to the server's.
+    ///
+    /// Also checks the `Vec<OutgoingRecord>` `apply_incoming` returns,
+    /// not just `db.get()`: a version that returned a pre-merge
+    /// `changes_since` snapshot instead of `sync`'s own resolved records
+    /// would still pass the `db.get()` assertions below, but would wrongly
+    /// report `remote-wins` as needing upload with its discarded local
+    /// value, which would silently revert the merge on the server.
+    #[test]
+    fn apply_incoming_merges_instead_of_overwriting() {
+        let store = open();
+        let db = Database::new(&store, "test");
+
+        // Establish a synced baseline for two keys.
+        db.store_incoming(&[
+            IncomingRecord {
+                key: Key::from("local-wins".to_string()),
+                value: Some(Value::from(serde_json::json!("base"))),
+                server_modified: 1,
+            },
+            IncomingRecord {
+                key: Key::from("remote-wins".to_string()),
+                value: Some(Value::from(serde_json::json!("base"))),
+                server_modified: 1,
+            },
+        ])
+        .expect("store_incoming failed");
+        db.apply_incoming().expect("apply_incoming failed");
+
+        // Change both keys locally, then let the server re-send one
+        // unchanged and the other changed.
+        db.put(
+            &[
+                (Key::from("local-wins".to_string()), Some(Value::from(serde_json::json!("local-edit")))),
+                (Key::from("remote-wins".to_string()), Some(Value::from(serde_json::json!("local-edit")))),
+            ],
+            &QuotaOptions::new(),
+        )
+        .expect("local put failed");
+
+        db.store_incoming(&[
+            IncomingRecord {
+                key: Key::from("local-wins".to_string()),
+                value: Some(Value::from(serde_json::json!("base"))),
+                server_modified: 2,
+            },
+            IncomingRecord {
+                key: Key::from("remote-wins".to_string()),
+                value: Some(Value::from(serde_json::json!("remote-edit"))),
+                server_modified: 2,
+            },
+        ])
+        .expect("store_incoming failed");
+        let outgoing = db.apply_incoming().expect("apply_incoming failed");
+
+        assert_eq!(
+            db.get(&Key::from("local-wins".to_string()), &GetOptions::new()).unwrap(),
+            Some(Value::from(serde_json::json!("local-edit"))),
+        );
+        assert_eq!(
+            db.get(&Key::from("remote-wins".to_string()), &GetOptions::new()).unwrap(),
+            Some(Value::from(serde_json::json!("remote-edit"))),
+        );
+
+        // `local-wins` still needs to be uploaded (the server hasn't seen
+        // it yet); `remote-wins` doesn't, since `data` now matches the
+        // server's value `sync` just resolved it to. A version that
+        // returned a stale pre-merge snapshot would wrongly include
+        // `remote-wins` here, carrying the local value `sync` discarded.
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(outgoing[0].key, Key::from("local-wins".to_string()));
+        assert_eq!(outgoing[0].value, Some(Value::from(serde_json::json!("local-edit"))));
+    }
 }
@@ -7,7 +7,11 @@
 //! This module implements the `nsIKeyValue` XPCOM interfaces that are
 //! exposed to C++ and chrome JS callers.
 
-use std::{io, ops::Bound, sync::Arc};
+use std::{
+    io,
+    ops::{Bound, ControlFlow},
+    sync::{mpsc, Arc},
+};
 
 use atomic_refcell::AtomicRefCell;
 use nserror::{nsresult, NS_OK};
@@ -18,8 +22,9 @@ use xpcom::{
     getter_addrefs,
     interfaces::{
         nsIAsyncShutdownClient, nsIAsyncShutdownService, nsIKeyValueDatabaseCallback,
-        nsIKeyValueEnumeratorCallback, nsIKeyValuePair, nsIKeyValueVariantCallback,
-        nsIKeyValueVoidCallback, nsIPropertyBag, nsIVariant,
+        nsIKeyValueEnumeratorCallback, nsIKeyValuePair, nsIKeyValuePropertyBagCallback,
+        nsIKeyValueSyncEngineCallback, nsIKeyValueVariantCallback, nsIKeyValueVoidCallback,
+        nsIPropertyBag, nsIVariant,
     },
     xpcom, xpcom_method, RefPtr,
 };
@@ -27,9 +32,9 @@ use xpcom::{
 use crate::skv::{
     abort::AbortError,
     coordinator::{Coordinator, CoordinatorClient, CoordinatorError},
-    database::{Database, DatabaseError, GetOptions},
+    database::{Database, DatabaseError, EnumerateOptions, GetOptions, QuotaOptions},
     key::{Key, KeyError},
-    store::{Store, StoreError, StorePath},
+    store::{RecoveryStrategy, Store, StoreError, StorePath},
     value::{Value, ValueError},
 };
 
@@ -97,19 +102,106 @@ impl KeyValueService {
     xpcom_method!(
         get_or_create_with_options => GetOrCreateWithOptions(
             callback: *const nsIKeyValueDatabaseCallback,
-            path: *const nsAString,
+            dir: *const nsAString,
             name: *const nsACString,
             strategy: u8
         )
     );
     fn get_or_create_with_options(
         &self,
-        _callback: &nsIKeyValueDatabaseCallback,
-        _path: &nsAString,
-        _name: &nsACString,
-        _strategy: u8,
-    ) -> Result<(), nsresult> {
-        Err(nserror::NS_ERROR_NOT_IMPLEMENTED)
+        callback: &nsIKeyValueDatabaseCallback,
+        dir: &nsAString,
+        name: &nsACString,
+        strategy: u8,
+    ) -> Result<(), Infallible> {
+        let client = self.client.clone();
+        let dir = nsString::from(dir);
+        let recovery = RecoveryStrategy::from_u8(strategy);
+        let request = moz_task::spawn_blocking(
+            "skv:KeyValueService:GetOrCreateWithOptions:Request",
+            async move {
+                let path = if dir == StorePath::IN_MEMORY_DATABASE_NAME {
+                    // In-memory stores can't be corrupt on disk, so the
+                    // recovery strategy is meaningless for them.
+                    StorePath::for_in_memory()
+                } else {
+                    StorePath::for_storage_dir(
+                        crate::fs::canonicalize(&*dir).map_err(InterfaceError::StorageDir)?,
+                    )
+                    .with_recovery_strategy(recovery)
+                };
+                Ok((client.child_with_name("skv:KeyValueDatabase")?, path))
+            },
+        );
+
+        let name = nsCString::from(name);
+        let callback = RefPtr::new(callback);
+        moz_task::spawn_local(
+            "skv:KeyValueService:GetOrCreateWithOptions:Response",
+            async move {
+                match request.await {
+                    Ok((client, path)) => {
+                        let db = KeyValueDatabase::new(client, path, name.to_utf8().into());
+                        unsafe { callback.Resolve(db.coerce()) }
+                    }
+                    Err::<_, InterfaceError>(err) => unsafe {
+                        callback.Reject(&*nsCString::from(err.to_string()))
+                    },
+                }
+            },
+        )
+        .detach();
+
+        Ok(())
+    }
+
+    xpcom_method!(
+        get_sync_engine => GetSyncEngine(
+            callback: *const nsIKeyValueSyncEngineCallback,
+            dir: *const nsAString,
+            name: *const nsACString
+        )
+    );
+    fn get_sync_engine(
+        &self,
+        callback: &nsIKeyValueSyncEngineCallback,
+        dir: &nsAString,
+        name: &nsACString,
+    ) -> Result<(), Infallible> {
+        let client = self.client.clone();
+        let dir = nsString::from(dir);
+        let request =
+            moz_task::spawn_blocking("skv:KeyValueService:GetSyncEngine:Request", async move {
+                let path = if dir == StorePath::IN_MEMORY_DATABASE_NAME {
+                    StorePath::for_in_memory()
+                } else {
+                    StorePath::for_storage_dir(
+                        crate::fs::canonicalize(&*dir).map_err(InterfaceError::StorageDir)?,
+                    )
+                };
+                Ok((client.child_with_name("skv:KeyValueSyncEngine")?, path))
+            });
+
+        let name = nsCString::from(name);
+        let callback = RefPtr::new(callback);
+        moz_task::spawn_local("skv:KeyValueService:GetSyncEngine:Response", async move {
+            match request.await {
+                Ok((client, path)) => {
+                    let engine = crate::skv::sync::KeyValueSyncEngine::new(
+                        client,
+                        path,
+                        name.to_utf8().into(),
+                    );
+                    unsafe { callback.Resolve(engine.coerce()) }
+                }
+                Err::<_, InterfaceError>(err) => unsafe {
+                    callback.Reject(&*nsCString::from(err.to_string()))
+                },
+            }
+        })
+        .detach();
+
+        Ok(())
     }
 }
 
@@ -153,7 +245,7 @@ impl KeyValueDatabase {
         let request = moz_task::spawn_blocking("skv:KeyValueDatabase:Put:Request", async move {
             let (store, key, value) = inputs?;
             let db = Database::new(&store, &name);
-            Ok(db.put(&[(key, value)])?)
+            Ok(db.put(&[(key, value)], &QuotaOptions::new())?)
         });
 
         let signal = self.client.signal();
@@ -203,7 +295,7 @@ impl KeyValueDatabase {
             moz_task::spawn_blocking("skv:KeyValueDatabase:WriteMany:Request", async move {
                 let (store, pairs) = inputs?;
                 let db = Database::new(&store, &name);
-                Ok(db.put(pairs.as_slice())?)
+                Ok(db.put(pairs.as_slice(), &QuotaOptions::new())?)
             });
 
         let signal = self.client.signal();
@@ -222,6 +314,105 @@ impl KeyValueDatabase {
         Ok(())
     }
 
+    xpcom_method!(
+        put_if_absent => PutIfAbsent(
+            callback: *const nsIKeyValueVariantCallback,
+            key: *const nsACString,
+            value: *const nsIVariant
+        )
+    );
+    fn put_if_absent(
+        &self,
+        callback: &nsIKeyValueVariantCallback,
+        key: &nsACString,
+        value: &nsIVariant,
+    ) -> Result<(), Infallible> {
+        let inputs = || -> Result<_, InterfaceError> {
+            let store = self.store()?;
+            let key = Key::try_from(key)?;
+            let value = Value::from_variant(value)?;
+            Ok((store, key, value))
+        }();
+
+        let name = self.name.clone();
+        let request =
+            moz_task::spawn_blocking("skv:KeyValueDatabase:PutIfAbsent:Request", async move {
+                let (store, key, value) = inputs?;
+                let db = Database::new(&store, &name);
+                Ok(db.put_if_absent(&key, &value, &QuotaOptions::new())?)
+            });
+
+        let signal = self.client.signal();
+        let callback = RefPtr::new(callback);
+        moz_task::spawn_local("skv:KeyValueDatabase:PutIfAbsent:Response", async move {
+            match signal.aborting(request).await {
+                Ok(swapped) => unsafe { callback.Resolve(swapped.into_variant().coerce()) },
+                Err(InterfaceError::Abort(_)) => unsafe {
+                    callback.Reject(&*nsCString::from("putIfAbsent: aborted"))
+                },
+                Err(err) => unsafe { callback.Reject(&*nsCString::from(err.to_string())) },
+            }
+        })
+        .detach();
+
+        Ok(())
+    }
+
+    xpcom_method!(
+        compare_and_swap => CompareAndSwap(
+            callback: *const nsIKeyValueVariantCallback,
+            key: *const nsACString,
+            expected_value: *const nsIVariant,
+            new_value: *const nsIVariant
+        )
+    );
+    fn compare_and_swap(
+        &self,
+        callback: &nsIKeyValueVariantCallback,
+        key: &nsACString,
+        expected_value: &nsIVariant,
+        new_value: &nsIVariant,
+    ) -> Result<(), Infallible> {
+        let inputs = || -> Result<_, InterfaceError> {
+            let store = self.store()?;
+            let key = Key::try_from(key)?;
+            // `null` means "must be absent"/"delete", for the expected and
+            // new values respectively.
+            let expected = match is_null_variant(expected_value)? {
+                true => None,
+                false => Some(Value::from_variant(expected_value)?),
+            };
+            let new = match is_null_variant(new_value)? {
+                true => None,
+                false => Some(Value::from_variant(new_value)?),
+            };
+            Ok((store, key, expected, new))
+        }();
+
+        let name = self.name.clone();
+        let request =
+            moz_task::spawn_blocking("skv:KeyValueDatabase:CompareAndSwap:Request", async move {
+                let (store, key, expected, new) = inputs?;
+                let db = Database::new(&store, &name);
+                Ok(db.compare_and_swap(&key, expected.as_ref(), new.as_ref(), &QuotaOptions::new())?)
+            });
+
+        let signal = self.client.signal();
+        let callback = RefPtr::new(callback);
+        moz_task::spawn_local("skv:KeyValueDatabase:CompareAndSwap:Response", async move {
+            match signal.aborting(request).await {
+                Ok(swapped) => unsafe { callback.Resolve(swapped.into_variant().coerce()) },
+                Err(InterfaceError::Abort(_)) => unsafe {
+                    callback.Reject(&*nsCString::from("compareAndSwap: aborted"))
+                },
+                Err(err) => unsafe { callback.Reject(&*nsCString::from(err.to_string())) },
+            }
+        })
+        .detach();
+
+        Ok(())
+    }
+
     xpcom_method!(
         get => Get(
             callback: *const nsIKeyValueVariantCallback,
@@ -374,6 +565,43 @@ impl KeyValueDatabase {
         Ok(())
     }
 
+    xpcom_method!(
+        get_stats => GetStats(callback: *const nsIKeyValuePropertyBagCallback)
+    );
+    fn get_stats(&self, callback: &nsIKeyValuePropertyBagCallback) -> Result<(), Infallible> {
+        let store = self.store();
+
+        let name = self.name.clone();
+        let request =
+            moz_task::spawn_blocking("skv:KeyValueDatabase:GetStats:Request", async move {
+                let store = store?;
+                let db = Database::new(&store, &name);
+                Ok(db.stats()?)
+            });
+
+        let signal = self.client.signal();
+        let callback = RefPtr::new(callback);
+        moz_task::spawn_local("skv:KeyValueDatabase:GetStats:Response", async move {
+            match signal.aborting(request).await {
+                Ok(stats) => {
+                    let bag = HashPropertyBag::new();
+                    bag.set("pairCount", (stats.pair_count as f64).into_variant());
+                    bag.set("fileSize", (stats.file_size as f64).into_variant());
+                    bag.set("walSize", (stats.wal_size as f64).into_variant());
+                    bag.set("pageSize", (stats.page_size as f64).into_variant());
+                    unsafe { callback.Resolve(bag.bag().coerce()) }
+                }
+                Err(InterfaceError::Abort(_)) => unsafe {
+                    callback.Reject(&*nsCString::from("getStats: aborted"))
+                },
+                Err(err) => unsafe { callback.Reject(&*nsCString::from(err.to_string())) },
+            }
+        })
+        .detach();
+
+        Ok(())
+    }
+
     xpcom_method!(
         enumerate => Enumerate(
             callback: *const nsIKeyValueEnumeratorCallback,
@@ -386,6 +614,28 @@ impl KeyValueDatabase {
         callback: &nsIKeyValueEnumeratorCallback,
         from_key: &nsACString,
         to_key: &nsACString,
+    ) -> Result<(), Infallible> {
+        self.enumerate_with_options(callback, from_key, to_key, &nsCString::new(), -1, false)
+    }
+
+    xpcom_method!(
+        enumerate_with_options => EnumerateWithOptions(
+            callback: *const nsIKeyValueEnumeratorCallback,
+            from_key: *const nsACString,
+            to_key: *const nsACString,
+            prefix: *const nsACString,
+            limit: i64,
+            reverse: bool
+        )
+    );
+    fn enumerate_with_options(
+        &self,
+        callback: &nsIKeyValueEnumeratorCallback,
+        from_key: &nsACString,
+        to_key: &nsACString,
+        prefix: &nsACString,
+        limit: i64,
+        reverse: bool,
     ) -> Result<(), Infallible> {
         let inputs = || -> Result<_, InterfaceError> {
             let store = self.store()?;
@@ -397,33 +647,49 @@ impl KeyValueDatabase {
                 true => Bound::Unbounded,
                 false => Bound::Excluded(Key::try_from(to_key)?),
             };
-            Ok((store, from_key, to_key))
+            let mut options = EnumerateOptions::new();
+            options.concurrent(true).reverse(reverse);
+            if !prefix.is_empty() {
+                options.prefix(Key::try_from(prefix)?);
+            }
+            if let Ok(limit) = u32::try_from(limit) {
+                options.limit(limit);
+            }
+            Ok((store, from_key, to_key, options))
         }();
 
+        // Unlike the other methods, enumeration doesn't run to completion
+        // on the blocking pool before resolving the callback: the cursor
+        // is stepped lazily, on demand, as `GetNext` is called, so large
+        // ranges don't have to be buffered up front. `sender` is moved
+        // into the task below and dropped (closing the channel) once the
+        // scan finishes or the receiving `KeyValueEnumerator` is freed.
+        let (sender, receiver) = mpsc::sync_channel(KeyValueEnumerator::PREFETCH_SIZE);
+
         let name = self.name.clone();
-        let request =
-            moz_task::spawn_blocking("skv:KeyValueDatabase:Enumerate:Request", async move {
-                let (store, from_key, to_key) = inputs?;
+        moz_task::spawn_blocking("skv:KeyValueDatabase:Enumerate:Cursor", async move {
+            let outcome = (|| -> Result<(), InterfaceError> {
+                let (store, from_key, to_key, options) = inputs?;
                 let db = Database::new(&store, &name);
-                Ok(db.enumerate((from_key, to_key), GetOptions::new().concurrent(true))?)
-            });
-
-        let signal = self.client.signal();
-        let callback = RefPtr::new(callback);
-        moz_task::spawn_local("skv:KeyValueDatabase:Enumerate:Response", async move {
-            match signal.aborting(request).await {
-                Ok(pairs) => {
-                    let enumerator = KeyValueEnumerator::new(pairs);
-                    unsafe { callback.Resolve(enumerator.coerce()) }
-                }
-                Err(InterfaceError::Abort(_)) => unsafe {
-                    callback.Reject(&*nsCString::from("enumerate: aborted"))
-                },
-                Err(err) => unsafe { callback.Reject(&*nsCString::from(err.to_string())) },
+                db.for_each((from_key, to_key), &options, |key, value| {
+                    match sender.send(Ok((key, value))) {
+                        Ok(()) => ControlFlow::Continue(()),
+                        // The receiver (and its `KeyValueEnumerator`) was
+                        // dropped; stop stepping the cursor.
+                        Err(_) => ControlFlow::Break(()),
+                    }
+                })?;
+                Ok(())
+            })();
+            if let Err(err) = outcome {
+                let _ = sender.send(Err(err));
             }
         })
         .detach();
 
+        let enumerator = KeyValueEnumerator::new(receiver);
+        unsafe { callback.Resolve(enumerator.coerce()) };
+
         Ok(())
     }
 
@@ -449,27 +715,61 @@ impl KeyValueDatabase {
     }
 }
 
+/// An enumerator backed by a live SQLite cursor, stepped on the
+/// coordinator's blocking pool.
+///
+/// Rather than buffering every matching pair up front, the cursor feeds
+/// pairs into a bounded channel as they're read from disk; `GetNext`
+/// blocks on that channel instead of an in-memory `Vec`, so large ranges
+/// cost constant memory.
 #[xpcom(implement(nsIKeyValueEnumerator), atomic)]
 pub struct KeyValueEnumerator {
-    iter: AtomicRefCell<std::vec::IntoIter<(Key, Value)>>,
+    receiver: mpsc::Receiver<Result<(Key, Value), InterfaceError>>,
+    next: AtomicRefCell<Option<(Key, Value)>>,
 }
 
 impl KeyValueEnumerator {
-    fn new(pairs: Vec<(Key, Value)>) -> RefPtr<Self> {
+    /// How many pairs the cursor is allowed to read ahead of the
+    /// consumer calling `GetNext`.
+    const PREFETCH_SIZE: usize = 16;
+
+    fn new(receiver: mpsc::Receiver<Result<(Key, Value), InterfaceError>>) -> RefPtr<Self> {
         KeyValueEnumerator::allocate(InitKeyValueEnumerator {
-            iter: AtomicRefCell::new(pairs.into_iter()),
+            receiver,
+            next: AtomicRefCell::new(None),
         })
     }
 
+    /// Pulls the next pair off the channel into `next`, if it isn't
+    /// already populated.
+    fn fill(&self) -> Result<(), InterfaceError> {
+        let mut next = self.next.borrow_mut();
+        if next.is_some() {
+            return Ok(());
+        }
+        *next = match self.receiver.recv() {
+            Ok(Ok(pair)) => Some(pair),
+            Ok(Err(err)) => return Err(err),
+            // The cursor task is done; there's nothing left to read.
+            Err(mpsc::RecvError) => None,
+        };
+        Ok(())
+    }
+
     xpcom_method!(has_more_elements => HasMoreElements() -> bool);
-    fn has_more_elements(&self) -> Result<bool, Infallible> {
-        Ok(!self.iter.borrow().as_slice().is_empty())
+    fn has_more_elements(&self) -> Result<bool, nsresult> {
+        self.fill().map_err(|_| nserror::NS_ERROR_FAILURE)?;
+        Ok(self.next.borrow().is_some())
     }
 
     xpcom_method!(get_next => GetNext() -> *const nsIKeyValuePair);
     fn get_next(&self) -> Result<RefPtr<nsIKeyValuePair>, nsresult> {
-        let mut iter = self.iter.borrow_mut();
-        let (key, value) = iter.next().ok_or(nserror::NS_ERROR_FAILURE)?;
+        self.fill().map_err(|_| nserror::NS_ERROR_FAILURE)?;
+        let (key, value) = self
+            .next
+            .borrow_mut()
+            .take()
+            .ok_or(nserror::NS_ERROR_FAILURE)?;
         let pair = KeyValuePair::new(key, value);
         Ok(RefPtr::new(pair.coerce()))
     }
@@ -588,6 +888,15 @@ impl KeyValueServiceShutdownBlocker {
     }
 }
 
+/// Whether `variant` is `null`/`undefined`, which `compareAndSwap` uses
+/// to mean "the key must be absent".
+fn is_null_variant(variant: &nsIVariant) -> Result<bool, InterfaceError> {
+    let mut data_type = 0u16;
+    unsafe { variant.GetDataType(&mut data_type) };
+    Ok(data_type == xpcom::interfaces::nsIDataType::VTYPE_EMPTY as u16
+        || data_type == xpcom::interfaces::nsIDataType::VTYPE_VOID as u16)
+}
+
 /// The error type for interface methods that never return an error.
 ///
 /// This is equivalent to [`std::convert::Infallible`], but implements
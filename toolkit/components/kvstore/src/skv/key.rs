@@ -0,0 +1,76 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Keys that identify values in a database.
+
+use std::borrow::Borrow;
+
+use nsstring::{nsACString, nsCString};
+use rusqlite::{
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+    ToSql,
+};
+
+/// A key that identifies a value in a named logical database.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Hash)]
+pub struct Key(String);
+
+impl Key {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<Key> for Key {
+    fn borrow(&self) -> &Key {
+        self
+    }
+}
+
+impl Borrow<str> for Key {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Key {
+    fn from(key: String) -> Self {
+        Key(key)
+    }
+}
+
+impl TryFrom<&nsACString> for Key {
+    type Error = KeyError;
+
+    fn try_from(value: &nsACString) -> Result<Self, Self::Error> {
+        Ok(Key(std::str::from_utf8(value)?.to_owned()))
+    }
+}
+
+impl From<Key> for nsCString {
+    fn from(key: Key) -> Self {
+        nsCString::from(key.0)
+    }
+}
+
+impl ToSql for Key {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.0.as_str()))
+    }
+}
+
+impl FromSql for Key {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()
+            .map(|key| Key(key.to_owned()))
+            .map_err(|err| FromSqlError::Other(Box::new(err)))
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum KeyError {
+    #[error("key is not valid UTF-8: {0}")]
+    InvalidUtf8(#[from] std::str::Utf8Error),
+}
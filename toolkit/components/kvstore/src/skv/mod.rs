@@ -23,6 +23,7 @@ mod key;
 mod schema;
 mod sql;
 mod store;
+mod sync;
 mod value;
 
 #[no_mangle]
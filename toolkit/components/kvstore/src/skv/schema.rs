@@ -0,0 +1,106 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The on-disk schema for a store's physical SQLite database.
+
+/// The current schema version.
+///
+/// Bumped from `1` to add `data.local_change_counter`, `tombstones`, and
+/// the `sync_*` tables that back [`crate::skv::sync`]. Bumped from `2` to
+/// add `incoming`, so records fetched from the server are staged there
+/// by `Database::store_incoming` instead of overwriting `mirror` (the
+/// three-way merge base `Database::sync` reads) before a sync round
+/// actually runs.
+pub const VERSION: i64 = 3;
+
+/// Creates the tables for a freshly opened or recreated
+/// database, and sets the schema version.
+pub fn init(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS dbs(
+           id INTEGER PRIMARY KEY,
+           name TEXT NOT NULL UNIQUE
+         );
+
+         CREATE TABLE IF NOT EXISTS data(
+           db_id INTEGER NOT NULL REFERENCES dbs(id) ON DELETE CASCADE,
+           key TEXT NOT NULL,
+           value BLOB NOT NULL,
+           -- Bumped on every local write; used to find changes made since
+           -- a sync engine's last sync. See `Database::changes_since`.
+           local_change_counter INTEGER NOT NULL DEFAULT 0,
+           PRIMARY KEY(db_id, key)
+         ) WITHOUT ROWID;
+
+         -- Records local deletions, so a sync engine can tell the server
+         -- about them even after the row is gone from `data`.
+         CREATE TABLE IF NOT EXISTS tombstones(
+           db_id INTEGER NOT NULL REFERENCES dbs(id) ON DELETE CASCADE,
+           key TEXT NOT NULL,
+           local_change_counter INTEGER NOT NULL,
+           PRIMARY KEY(db_id, key)
+         ) WITHOUT ROWID;
+
+         -- A monotonic source for `local_change_counter`, shared by every
+         -- named database in the store.
+         CREATE TABLE IF NOT EXISTS local_change_counter(
+           id INTEGER PRIMARY KEY CHECK(id = 0),
+           next INTEGER NOT NULL DEFAULT 1
+         );
+         INSERT OR IGNORE INTO local_change_counter(id, next) VALUES(0, 1);
+
+         -- The last value a sync engine fetched from (or wrote to) the
+         -- server for each key; the common base for a three-way merge.
+         -- See `skv::sync`.
+         CREATE TABLE IF NOT EXISTS mirror(
+           db_id INTEGER NOT NULL REFERENCES dbs(id) ON DELETE CASCADE,
+           key TEXT NOT NULL,
+           value BLOB,
+           server_modified INTEGER NOT NULL,
+           PRIMARY KEY(db_id, key)
+         ) WITHOUT ROWID;
+
+         -- Records fetched from the server by `Database::store_incoming`,
+         -- staged here until the next `Database::apply_incoming` merges
+         -- them and clears the staged rows. Kept separate from `mirror`
+         -- so the merge can still read `mirror`'s pre-sync value as the
+         -- three-way merge base.
+         CREATE TABLE IF NOT EXISTS incoming(
+           db_id INTEGER NOT NULL REFERENCES dbs(id) ON DELETE CASCADE,
+           key TEXT NOT NULL,
+           value BLOB,
+           server_modified INTEGER NOT NULL,
+           PRIMARY KEY(db_id, key)
+         ) WITHOUT ROWID;
+
+         -- Per-database sync bookkeeping: the sync engine's GUID, and the
+         -- last-sync token / timestamp used to resume an incremental sync.
+         CREATE TABLE IF NOT EXISTS sync_meta(
+           db_id INTEGER PRIMARY KEY REFERENCES dbs(id) ON DELETE CASCADE,
+           sync_id TEXT,
+           last_sync INTEGER NOT NULL DEFAULT 0
+         );
+        ",
+    )?;
+    conn.pragma_update(None, "user_version", VERSION)?;
+    Ok(())
+}
+
+/// Bumps the store's local change counter by `count` in one round-trip,
+/// and returns the start of the reserved range: callers stamp
+/// `start..start + count` onto the `count` rows they're writing in the
+/// same transaction, rather than issuing one round-trip per row.
+pub fn reserve_local_change_counters(
+    tx: &rusqlite::Transaction<'_>,
+    count: i64,
+) -> rusqlite::Result<i64> {
+    tx.query_row(
+        "UPDATE local_change_counter
+         SET next = next + :count
+         WHERE id = 0
+         RETURNING next - :count",
+        rusqlite::named_params! { ":count": count },
+        |row| row.get(0),
+    )
+}
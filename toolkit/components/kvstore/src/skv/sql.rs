@@ -0,0 +1,141 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Helpers for building `WHERE` clause fragments.
+
+use std::{
+    fmt,
+    ops::{Bound, RangeBounds},
+};
+
+use rusqlite::ToSql;
+
+use crate::skv::key::Key;
+
+/// A `WHERE` clause fragment that restricts a query to a range of keys,
+/// and the named parameters it binds.
+///
+/// Displaying a `RangeFragment` produces the SQL text; `start_param` and
+/// `end_param` give the corresponding `rusqlite` named parameters, which
+/// are only present when the range is actually bounded on that side.
+pub struct RangeFragment {
+    column: &'static str,
+    start: Option<(Key, bool)>,
+    end: Option<(Key, bool)>,
+}
+
+impl RangeFragment {
+    pub fn new(column: &'static str, range: &impl RangeBounds<Key>) -> Self {
+        let start = match range.start_bound() {
+            Bound::Included(key) => Some((key.clone(), true)),
+            Bound::Excluded(key) => Some((key.clone(), false)),
+            Bound::Unbounded => None,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => Some((key.clone(), true)),
+            Bound::Excluded(key) => Some((key.clone(), false)),
+            Bound::Unbounded => None,
+        };
+        Self { column, start, end }
+    }
+
+    pub fn start_param(&self) -> Option<(&'static str, &dyn ToSql)> {
+        self.start
+            .as_ref()
+            .map(|(key, _)| (":range_start", key as &dyn ToSql))
+    }
+
+    pub fn end_param(&self) -> Option<(&'static str, &dyn ToSql)> {
+        self.end
+            .as_ref()
+            .map(|(key, _)| (":range_end", key as &dyn ToSql))
+    }
+}
+
+impl fmt::Display for RangeFragment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut clauses = Vec::with_capacity(2);
+        if let Some((_, inclusive)) = &self.start {
+            let op = if *inclusive { ">=" } else { ">" };
+            clauses.push(format!("{} {op} :range_start", self.column));
+        }
+        if let Some((_, inclusive)) = &self.end {
+            let op = if *inclusive { "<=" } else { "<" };
+            clauses.push(format!("{} {op} :range_end", self.column));
+        }
+        if clauses.is_empty() {
+            write!(f, "1")
+        } else {
+            write!(f, "{}", clauses.join(" AND "))
+        }
+    }
+}
+
+/// A `WHERE` clause fragment that restricts a query to keys starting
+/// with a prefix, via a case-sensitive `GLOB` pattern with `*`, `?`, and
+/// `[` escaped (as `[*]`, `[?]`, and `[[]`) so they match literally
+/// rather than as wildcards.
+pub struct PrefixFragment {
+    column: &'static str,
+    pattern: Option<String>,
+}
+
+impl PrefixFragment {
+    pub fn new(column: &'static str, prefix: Option<&Key>) -> Self {
+        let pattern = prefix.map(|prefix| {
+            let mut pattern = String::with_capacity(prefix.as_str().len() + 1);
+            for ch in prefix.as_str().chars() {
+                if ch == '*' || ch == '?' || ch == '[' {
+                    pattern.push('[');
+                    pattern.push(ch);
+                    pattern.push(']');
+                } else {
+                    pattern.push(ch);
+                }
+            }
+            pattern.push('*');
+            pattern
+        });
+        Self { column, pattern }
+    }
+
+    pub fn param(&self) -> Option<(&'static str, &dyn ToSql)> {
+        self.pattern
+            .as_ref()
+            .map(|pattern| (":prefix", pattern as &dyn ToSql))
+    }
+}
+
+impl fmt::Display for PrefixFragment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.pattern {
+            Some(_) => write!(f, "{} GLOB :prefix", self.column),
+            None => write!(f, "1"),
+        }
+    }
+}
+
+/// SQLite's default limit on the number of bound parameters a single
+/// statement may use (`SQLITE_MAX_VARIABLE_NUMBER`'s default; see
+/// <https://sqlite.org/limits.html#max_variable_number>).
+const SQLITE_MAX_VARIABLE_NUMBER: usize = 999;
+
+/// Splits `items` into chunks sized so that a statement binding
+/// `columns_per_row` parameters per item, plus one shared parameter
+/// (like a `:name` repeated across every row), stays within SQLite's
+/// limit on bound parameters, and calls `f` once per chunk.
+///
+/// Borrowed from sql-support's `each_chunk`, for building multi-row
+/// `INSERT`/`DELETE` statements instead of one statement per row.
+pub fn each_chunk<T>(
+    items: &[T],
+    columns_per_row: usize,
+    mut f: impl FnMut(&[T]) -> rusqlite::Result<()>,
+) -> rusqlite::Result<()> {
+    let chunk_size = ((SQLITE_MAX_VARIABLE_NUMBER - 1) / columns_per_row).max(1);
+    for chunk in items.chunks(chunk_size) {
+        f(chunk)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,420 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A store is a single physical SQLite database, shared by every named
+//! logical [`crate::skv::database::Database`] within it.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{backup::Backup, Connection, OpenFlags};
+
+use crate::skv::{
+    connection::{Reader, Writer},
+    key::Key,
+    schema,
+    value::Value,
+};
+
+/// A single change to a key in a database, delivered to observers after
+/// the transaction that produced it commits.
+///
+/// Mirrors the diff-then-notify model `storage.onChanged` listeners see
+/// in the webext_storage component: `old_value`/`new_value` are `None`
+/// when the key didn't exist before/after the change, and no record is
+/// produced at all when a write doesn't actually change the value.
+#[derive(Clone, Debug)]
+pub struct ChangeRecord {
+    pub db_name: String,
+    pub key: Key,
+    pub old_value: Option<Value>,
+    pub new_value: Option<Value>,
+}
+
+/// A callback registered with [`Store::add_observer`], invoked with a
+/// batch of changes after each committed transaction that produced them.
+pub type Observer = Box<dyn Fn(&[ChangeRecord]) + Send + Sync>;
+
+/// How many of a database's pages an in-progress [`Store::backup_to`]
+/// call has copied so far.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Progress {
+    pub pages_done: i32,
+    pub pages_total: i32,
+}
+
+/// What to do when opening a store's physical SQLite file fails because
+/// the file is corrupt.
+///
+/// This mirrors the `RecoveryStrategy` that the rkv-based kvstore backend
+/// used to expose.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RecoveryStrategy {
+    /// Surface the failure to the caller; don't touch the file.
+    Error,
+    /// Delete the corrupt database (and its `-wal`/`-shm` siblings) and
+    /// recreate an empty one in its place.
+    Discard,
+    /// Move the corrupt database (and its `-wal`/`-shm` siblings) aside to
+    /// a uniquely-suffixed path, then create an empty database in their
+    /// place, so the caller can recover the old bytes out of band.
+    Rename,
+}
+
+impl RecoveryStrategy {
+    /// Maps the `strategy` byte accepted by `nsIKeyValueService::
+    /// getOrCreateWithOptions` to a `RecoveryStrategy`.
+    pub fn from_u8(strategy: u8) -> Self {
+        match strategy {
+            1 => RecoveryStrategy::Discard,
+            2 => RecoveryStrategy::Rename,
+            _ => RecoveryStrategy::Error,
+        }
+    }
+}
+
+impl Default for RecoveryStrategy {
+    fn default() -> Self {
+        RecoveryStrategy::Error
+    }
+}
+
+/// The location of a store's physical SQLite database, and what to do if
+/// it turns out to be corrupt when opened.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StorePath {
+    location: StoreLocation,
+    recovery: RecoveryStrategy,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum StoreLocation {
+    OnDisk(PathBuf),
+    InMemory,
+}
+
+impl StorePath {
+    /// The special `dir` name passed to `getOrCreate` that requests an
+    /// in-memory, non-persistent store.
+    pub const IN_MEMORY_DATABASE_NAME: &'static str = ":memory:";
+
+    const FILE_NAME: &'static str = "data.sqlite";
+
+    pub fn for_in_memory() -> Self {
+        Self {
+            location: StoreLocation::InMemory,
+            recovery: RecoveryStrategy::Error,
+        }
+    }
+
+    pub fn for_storage_dir(dir: impl Into<PathBuf>) -> Self {
+        let mut path = dir.into();
+        path.push(Self::FILE_NAME);
+        Self {
+            location: StoreLocation::OnDisk(path),
+            recovery: RecoveryStrategy::Error,
+        }
+    }
+
+    /// Sets the strategy for recovering from a corrupt database file.
+    ///
+    /// Has no effect on in-memory stores.
+    pub fn with_recovery_strategy(mut self, recovery: RecoveryStrategy) -> Self {
+        self.recovery = recovery;
+        self
+    }
+
+    fn as_path(&self) -> Option<&Path> {
+        match &self.location {
+            StoreLocation::OnDisk(path) => Some(path),
+            StoreLocation::InMemory => None,
+        }
+    }
+
+    /// The `-wal` and `-shm` siblings of an on-disk database file.
+    fn siblings(path: &Path) -> [PathBuf; 2] {
+        let wal = append_to_file_name(path, "-wal");
+        let shm = append_to_file_name(path, "-shm");
+        [wal, shm]
+    }
+}
+
+fn append_to_file_name(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(|n| n.to_owned()).unwrap_or_default();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// A single physical SQLite database, holding one read-write connection
+/// and one read-only connection.
+pub struct Store {
+    path: StorePath,
+    writer: Mutex<Connection>,
+    reader: Mutex<Connection>,
+    observers: RwLock<Vec<Observer>>,
+}
+
+impl Store {
+    /// Opens the store at `path`, applying `path`'s recovery strategy if
+    /// the database turns out to be corrupt.
+    pub fn open(path: &StorePath) -> Result<Self, StoreError> {
+        match path.as_path() {
+            None => Self::open_in_memory(path.clone()),
+            Some(file) => Self::open_on_disk(file, path.recovery, path.clone()),
+        }
+    }
+
+    fn open_in_memory(path: StorePath) -> Result<Self, StoreError> {
+        let writer = Connection::open_in_memory()?;
+        writer.pragma_update(None, "foreign_keys", "ON")?;
+        schema::init(&writer)?;
+        let reader = Connection::open_in_memory()?;
+        reader.pragma_update(None, "foreign_keys", "ON")?;
+        Ok(Self {
+            path,
+            writer: Mutex::new(writer),
+            reader: Mutex::new(reader),
+            observers: RwLock::new(Vec::new()),
+        })
+    }
+
+    fn open_on_disk(file: &Path, recovery: RecoveryStrategy, path: StorePath) -> Result<Self, StoreError> {
+        match Self::open_and_init(file, path.clone()) {
+            Ok(store) => Ok(store),
+            Err(err) if err.is_corrupt() => match recovery {
+                RecoveryStrategy::Error => Err(err),
+                RecoveryStrategy::Discard => {
+                    Self::discard(file)?;
+                    Self::open_and_init(file, path)
+                }
+                RecoveryStrategy::Rename => {
+                    Self::rename_aside(file)?;
+                    Self::open_and_init(file, path)
+                }
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    fn open_and_init(file: &Path, path: StorePath) -> Result<Self, StoreError> {
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let writer = Connection::open(file)?;
+        // A connection can be handed a corrupt file and still succeed at
+        // `open`; only a query against it fails. Force the corruption (if
+        // any) to surface now, before we report success.
+        writer
+            .pragma_query_value(None, "integrity_check", |row| row.get::<_, String>(0))
+            .map_err(StoreError::from)
+            .and_then(|result| {
+                if result == "ok" {
+                    Ok(())
+                } else {
+                    Err(StoreError::Corrupt)
+                }
+            })?;
+        // The schema declares `ON DELETE CASCADE` on every table's
+        // reference to `dbs`, which SQLite only enforces when
+        // `foreign_keys` is turned on for the connection.
+        writer.pragma_update(None, "foreign_keys", "ON")?;
+        schema::init(&writer)?;
+        let reader = Connection::open_with_flags(
+            file,
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        reader.pragma_update(None, "foreign_keys", "ON")?;
+        Ok(Self {
+            path,
+            writer: Mutex::new(writer),
+            reader: Mutex::new(reader),
+            observers: RwLock::new(Vec::new()),
+        })
+    }
+
+    /// Deletes the database file and its `-wal`/`-shm` siblings.
+    fn discard(file: &Path) -> Result<(), StoreError> {
+        remove_if_exists(file)?;
+        for sibling in StorePath::siblings(file) {
+            remove_if_exists(&sibling)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the database file and its `-wal`/`-shm` siblings aside to a
+    /// uniquely-suffixed path.
+    fn rename_aside(file: &Path) -> Result<(), StoreError> {
+        let suffix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros())
+            .unwrap_or(0);
+        rename_if_exists(file, &append_to_file_name(file, &format!(".corrupt-{suffix}")))?;
+        for sibling in StorePath::siblings(file) {
+            let renamed = append_to_file_name(&sibling, &format!(".corrupt-{suffix}"));
+            rename_if_exists(&sibling, &renamed)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the read-only connection. An in-memory store's `reader`
+    /// connection is a separate, empty database, so for an in-memory
+    /// store this returns the read-write connection instead.
+    pub fn reader(&self) -> Result<Reader<'_>, StoreError> {
+        let conn = match self.path.as_path() {
+            Some(_) => &self.reader,
+            None => &self.writer,
+        };
+        Ok(Reader(conn.lock().map_err(|_| StoreError::Poisoned)?))
+    }
+
+    pub fn writer(&self) -> Result<Writer<'_>, StoreError> {
+        Ok(Writer(self.writer.lock().map_err(|_| StoreError::Poisoned)?))
+    }
+
+    /// Registers `observer` to be called with every batch of changes
+    /// committed from now on.
+    pub fn add_observer(&self, observer: Observer) {
+        if let Ok(mut observers) = self.observers.write() {
+            observers.push(observer);
+        }
+    }
+
+    /// Delivers `changes` to every registered observer.
+    ///
+    /// Callers must only invoke this after the transaction that produced
+    /// `changes` has committed, so observers never see rolled-back state.
+    pub(crate) fn notify(&self, changes: &[ChangeRecord]) {
+        if changes.is_empty() {
+            return;
+        }
+        if let Ok(observers) = self.observers.read() {
+            for observer in observers.iter() {
+                observer(changes);
+            }
+        }
+    }
+
+    /// Copies every page of the store's database to a fresh database file
+    /// at `dest`, calling `progress` after each incremental step so
+    /// callers can report or throttle long-running snapshots.
+    ///
+    /// Takes the writer lock for the duration of the copy, but steps the
+    /// backup a page range at a time (rather than all at once) so readers
+    /// using `concurrent` [`crate::skv::database::GetOptions`] can still
+    /// make progress between steps.
+    pub fn backup_to(&self, dest: &Path, progress: impl FnMut(Progress)) -> Result<(), StoreError> {
+        let writer = self.writer.lock().map_err(|_| StoreError::Poisoned)?;
+        let mut dest_conn = Connection::open(dest)?;
+        let backup = Backup::new(&writer, &mut dest_conn)?;
+        Self::step_to_completion(&backup, progress)
+    }
+
+    /// Overwrites the store's database with the contents of the database
+    /// file at `src`, replacing it a page range at a time.
+    ///
+    /// Also holds `self.reader`'s lock for the duration, not just
+    /// `self.writer`'s: `self.reader` is a separate connection open on
+    /// the same on-disk file `writer` backs, and rusqlite's backup API
+    /// requires that no other connection touch the destination file
+    /// while a backup into it is in progress.
+    pub fn restore_from(&self, src: &Path) -> Result<(), StoreError> {
+        let mut writer = self.writer.lock().map_err(|_| StoreError::Poisoned)?;
+        let _reader = self.reader.lock().map_err(|_| StoreError::Poisoned)?;
+        let src_conn = Connection::open(src)?;
+        let backup = Backup::new(&src_conn, &mut writer)?;
+        Self::step_to_completion(&backup, |_| {})
+    }
+
+    /// Steps `backup` to completion, retrying on `Busy`/`Locked` (a
+    /// concurrent reader holding a page the backup needs) and reporting
+    /// progress after every step that isn't immediately done.
+    ///
+    /// Backs off with a short sleep between retries, rather than spinning
+    /// on `std::thread::yield_now()`, so a backup contending with a
+    /// concurrent reader doesn't peg a CPU core while it waits.
+    fn step_to_completion(
+        backup: &Backup<'_, '_>,
+        mut progress: impl FnMut(Progress),
+    ) -> Result<(), StoreError> {
+        const PAGES_PER_STEP: i32 = 100;
+        const RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(5);
+        loop {
+            match backup.step(PAGES_PER_STEP)? {
+                rusqlite::backup::StepResult::Done => return Ok(()),
+                rusqlite::backup::StepResult::More => {
+                    let p = backup.progress();
+                    progress(Progress {
+                        pages_done: p.pagecount - p.remaining,
+                        pages_total: p.pagecount,
+                    });
+                }
+                rusqlite::backup::StepResult::Busy | rusqlite::backup::StepResult::Locked => {
+                    std::thread::sleep(RETRY_DELAY);
+                }
+            }
+        }
+    }
+
+    /// The size, in bytes, of the store's `-wal` file, or `0` for an
+    /// in-memory store or one with no outstanding WAL frames.
+    pub fn wal_size(&self) -> Result<u64, StoreError> {
+        match self.path.as_path() {
+            Some(file) => match fs::metadata(StorePath::siblings(file)[0].as_path()) {
+                Ok(metadata) => Ok(metadata.len()),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+                Err(err) => Err(err.into()),
+            },
+            None => Ok(0),
+        }
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum StoreError {
+    #[error("sqlite: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("io: {0}")]
+    Io(#[from] io::Error),
+    #[error("database is corrupt")]
+    Corrupt,
+    #[error("store connection lock is poisoned")]
+    Poisoned,
+}
+
+impl StoreError {
+    /// Whether this error indicates that the database file is corrupt,
+    /// as opposed to some other failure (permissions, disk full, ...).
+    fn is_corrupt(&self) -> bool {
+        match self {
+            StoreError::Corrupt => true,
+            StoreError::Sqlite(rusqlite::Error::SqliteFailure(err, _)) => matches!(
+                err.code,
+                rusqlite::ErrorCode::DatabaseCorrupt | rusqlite::ErrorCode::NotADatabase
+            ),
+            _ => false,
+        }
+    }
+}
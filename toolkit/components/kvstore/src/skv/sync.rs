@@ -0,0 +1,436 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! An optional bridge that exposes a named database as a
+//! `mozIBridgedSyncEngine`, the same way `webext_storage_bridge` bridges
+//! a `golden_gate::BridgedEngine` into Firefox Sync.
+//!
+//! Unlike the other `nsIKeyValue*` interfaces, this one is driven by the
+//! Sync engine rather than by arbitrary JS callers, and its callback
+//! (`mozIBridgedSyncEngineCallback`) uses `handleSuccess`/`handleError`
+//! instead of `Resolve`/`Reject`. Otherwise, every method follows the
+//! same spawn-on-the-blocking-pool-then-resolve-on-the-caller's-thread
+//! shape as the rest of `skv`.
+
+use std::sync::Arc;
+
+use nserror::nsresult;
+use nsstring::{nsACString, nsCString};
+use storage_variant::VariantType;
+use thin_vec::ThinVec;
+use xpcom::{
+    interfaces::{mozIBridgedSyncEngineApplyCallback, mozIBridgedSyncEngineCallback},
+    xpcom, xpcom_method, RefPtr,
+};
+
+use crate::skv::{
+    coordinator::{CoordinatorClient, CoordinatorError},
+    database::{Database, DatabaseError, IncomingRecord, OutgoingRecord},
+    key::Key,
+    store::{Store, StoreError, StorePath},
+    value::Value,
+};
+
+/// The JSON shape of a sync record on the wire, matching the
+/// `{id, data}` envelopes that `golden_gate`-based bridged engines (like
+/// `webext_storage_bridge`) already use. `data` is the JSON-encoded
+/// value, or absent for a tombstone.
+#[derive(serde::Deserialize, serde::Serialize)]
+struct Envelope {
+    id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<serde_json::Value>,
+    #[serde(default)]
+    modified: i64,
+}
+
+#[xpcom(implement(mozIBridgedSyncEngine), atomic)]
+pub struct KeyValueSyncEngine {
+    client: CoordinatorClient<'static>,
+    path: StorePath,
+    name: String,
+}
+
+impl KeyValueSyncEngine {
+    pub fn new(client: CoordinatorClient<'static>, path: StorePath, name: String) -> RefPtr<Self> {
+        KeyValueSyncEngine::allocate(InitKeyValueSyncEngine { client, path, name })
+    }
+
+    fn store(&self) -> Result<Arc<Store>, SyncError> {
+        Ok(self.client.store_for_path(self.path.clone())?)
+    }
+
+    xpcom_method!(
+        get_last_sync => GetLastSync(callback: *const mozIBridgedSyncEngineCallback)
+    );
+    fn get_last_sync(&self, callback: &mozIBridgedSyncEngineCallback) -> Result<(), Infallible> {
+        let store = self.store();
+        let name = self.name.clone();
+        let request =
+            moz_task::spawn_blocking("skv:KeyValueSyncEngine:GetLastSync:Request", async move {
+                let db = Database::new(&store?, &name);
+                Ok(db.last_sync()?)
+            });
+
+        let callback = RefPtr::new(callback);
+        moz_task::spawn_local("skv:KeyValueSyncEngine:GetLastSync:Response", async move {
+            match request.await {
+                Ok(last_sync) => unsafe {
+                    callback.HandleSuccess((last_sync as f64).into_variant().coerce())
+                },
+                Err::<_, SyncError>(err) => unsafe {
+                    callback.HandleError(err.into(), &*nsCString::from(err.to_string()))
+                },
+            }
+        })
+        .detach();
+
+        Ok(())
+    }
+
+    xpcom_method!(
+        set_last_sync => SetLastSync(
+            callback: *const mozIBridgedSyncEngineCallback,
+            last_sync_millis: i64
+        )
+    );
+    fn set_last_sync(
+        &self,
+        callback: &mozIBridgedSyncEngineCallback,
+        last_sync_millis: i64,
+    ) -> Result<(), Infallible> {
+        let store = self.store();
+        let name = self.name.clone();
+        self.spawn_void(
+            "skv:KeyValueSyncEngine:SetLastSync:Request",
+            "skv:KeyValueSyncEngine:SetLastSync:Response",
+            callback,
+            async move {
+                let db = Database::new(&store?, &name);
+                Ok(db.set_last_sync(last_sync_millis)?)
+            },
+        );
+        Ok(())
+    }
+
+    xpcom_method!(
+        get_sync_id => GetSyncId(callback: *const mozIBridgedSyncEngineCallback)
+    );
+    fn get_sync_id(&self, callback: &mozIBridgedSyncEngineCallback) -> Result<(), Infallible> {
+        let store = self.store();
+        let name = self.name.clone();
+        let request =
+            moz_task::spawn_blocking("skv:KeyValueSyncEngine:GetSyncId:Request", async move {
+                let db = Database::new(&store?, &name);
+                Ok(db.sync_id()?)
+            });
+
+        let callback = RefPtr::new(callback);
+        moz_task::spawn_local("skv:KeyValueSyncEngine:GetSyncId:Response", async move {
+            match request.await {
+                Ok(Some(sync_id)) => unsafe {
+                    callback.HandleSuccess(sync_id.as_str().into_variant().coerce())
+                },
+                Ok(None) => unsafe { callback.HandleSuccess(false.into_variant().coerce()) },
+                Err::<_, SyncError>(err) => unsafe {
+                    callback.HandleError(err.into(), &*nsCString::from(err.to_string()))
+                },
+            }
+        })
+        .detach();
+
+        Ok(())
+    }
+
+    xpcom_method!(
+        reset_sync_id => ResetSyncId(callback: *const mozIBridgedSyncEngineCallback)
+    );
+    fn reset_sync_id(&self, callback: &mozIBridgedSyncEngineCallback) -> Result<(), Infallible> {
+        self.assign_sync_id(new_random_sync_id(), callback)
+    }
+
+    xpcom_method!(
+        ensure_current_sync_id => EnsureCurrentSyncId(
+            callback: *const mozIBridgedSyncEngineCallback,
+            new_sync_id: *const nsACString
+        )
+    );
+    fn ensure_current_sync_id(
+        &self,
+        callback: &mozIBridgedSyncEngineCallback,
+        new_sync_id: &nsACString,
+    ) -> Result<(), Infallible> {
+        self.assign_sync_id(new_sync_id.to_string(), callback)
+    }
+
+    /// Shared by `resetSyncId` (which always assigns a fresh, random
+    /// GUID) and `ensureCurrentSyncId` (which assigns the GUID the
+    /// server already has).
+    fn assign_sync_id(
+        &self,
+        sync_id: String,
+        callback: &mozIBridgedSyncEngineCallback,
+    ) -> Result<(), Infallible> {
+        let store = self.store();
+        let name = self.name.clone();
+        let request =
+            moz_task::spawn_blocking("skv:KeyValueSyncEngine:AssignSyncId:Request", async move {
+                let db = Database::new(&store?, &name);
+                db.set_sync_id(&sync_id)?;
+                Ok(sync_id)
+            });
+
+        let callback = RefPtr::new(callback);
+        moz_task::spawn_local("skv:KeyValueSyncEngine:AssignSyncId:Response", async move {
+            match request.await {
+                Ok(sync_id) => unsafe {
+                    callback.HandleSuccess(sync_id.as_str().into_variant().coerce())
+                },
+                Err::<_, SyncError>(err) => unsafe {
+                    callback.HandleError(err.into(), &*nsCString::from(err.to_string()))
+                },
+            }
+        })
+        .detach();
+
+        Ok(())
+    }
+
+    xpcom_method!(
+        sync_started => SyncStarted(callback: *const mozIBridgedSyncEngineCallback)
+    );
+    fn sync_started(&self, callback: &mozIBridgedSyncEngineCallback) -> Result<(), Infallible> {
+        // The store is always ready to read and write; there's no setup
+        // to do before a sync starts.
+        self.spawn_void(
+            "skv:KeyValueSyncEngine:SyncStarted:Request",
+            "skv:KeyValueSyncEngine:SyncStarted:Response",
+            callback,
+            async move { Ok(()) },
+        );
+        Ok(())
+    }
+
+    xpcom_method!(
+        store_incoming => StoreIncoming(
+            callback: *const mozIBridgedSyncEngineCallback,
+            envelopes_as_json: *const ThinVec<nsCString>
+        )
+    );
+    fn store_incoming(
+        &self,
+        callback: &mozIBridgedSyncEngineCallback,
+        envelopes_as_json: &ThinVec<nsCString>,
+    ) -> Result<(), Infallible> {
+        let store = self.store();
+        let name = self.name.clone();
+        let envelopes = envelopes_as_json
+            .iter()
+            .map(|envelope| serde_json::from_slice::<Envelope>(envelope))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(SyncError::Envelope);
+        self.spawn_void(
+            "skv:KeyValueSyncEngine:StoreIncoming:Request",
+            "skv:KeyValueSyncEngine:StoreIncoming:Response",
+            callback,
+            async move {
+                let db = Database::new(&store?, &name);
+                let records = envelopes?.into_iter().map(IncomingRecord::from).collect::<Vec<_>>();
+                Ok(db.store_incoming(&records)?)
+            },
+        );
+        Ok(())
+    }
+
+    xpcom_method!(
+        apply => Apply(callback: *const mozIBridgedSyncEngineApplyCallback)
+    );
+    fn apply(&self, callback: &mozIBridgedSyncEngineApplyCallback) -> Result<(), Infallible> {
+        let store = self.store();
+        let name = self.name.clone();
+        let request = moz_task::spawn_blocking("skv:KeyValueSyncEngine:Apply:Request", async move {
+            let db = Database::new(&store?, &name);
+            Ok(db.apply_incoming()?)
+        });
+
+        let callback = RefPtr::new(callback);
+        moz_task::spawn_local("skv:KeyValueSyncEngine:Apply:Response", async move {
+            match request.await {
+                Ok(outgoing) => {
+                    let envelopes: ThinVec<nsCString> = outgoing
+                        .into_iter()
+                        .filter_map(|record| serde_json::to_vec(&Envelope::from(record)).ok())
+                        .map(nsCString::from)
+                        .collect();
+                    unsafe { callback.HandleSuccess(&envelopes) };
+                }
+                Err::<_, SyncError>(err) => unsafe {
+                    callback.HandleError(err.into(), &*nsCString::from(err.to_string()))
+                },
+            }
+        })
+        .detach();
+
+        Ok(())
+    }
+
+    xpcom_method!(
+        set_uploaded => SetUploaded(
+            callback: *const mozIBridgedSyncEngineCallback,
+            server_modified_millis: i64,
+            uploaded_ids: *const ThinVec<nsCString>
+        )
+    );
+    fn set_uploaded(
+        &self,
+        callback: &mozIBridgedSyncEngineCallback,
+        server_modified_millis: i64,
+        uploaded_ids: &ThinVec<nsCString>,
+    ) -> Result<(), Infallible> {
+        let store = self.store();
+        let name = self.name.clone();
+        let keys: Vec<Key> = uploaded_ids.iter().map(|id| Key::from(id.to_string())).collect();
+        self.spawn_void(
+            "skv:KeyValueSyncEngine:SetUploaded:Request",
+            "skv:KeyValueSyncEngine:SetUploaded:Response",
+            callback,
+            async move {
+                let db = Database::new(&store?, &name);
+                Ok(db.set_uploaded(server_modified_millis, &keys)?)
+            },
+        );
+        Ok(())
+    }
+
+    xpcom_method!(
+        sync_finished => SyncFinished(callback: *const mozIBridgedSyncEngineCallback)
+    );
+    fn sync_finished(&self, callback: &mozIBridgedSyncEngineCallback) -> Result<(), Infallible> {
+        self.spawn_void(
+            "skv:KeyValueSyncEngine:SyncFinished:Request",
+            "skv:KeyValueSyncEngine:SyncFinished:Response",
+            callback,
+            async move { Ok(()) },
+        );
+        Ok(())
+    }
+
+    xpcom_method!(
+        reset => Reset(callback: *const mozIBridgedSyncEngineCallback)
+    );
+    fn reset(&self, callback: &mozIBridgedSyncEngineCallback) -> Result<(), Infallible> {
+        let store = self.store();
+        let name = self.name.clone();
+        self.spawn_void(
+            "skv:KeyValueSyncEngine:Reset:Request",
+            "skv:KeyValueSyncEngine:Reset:Response",
+            callback,
+            async move {
+                let db = Database::new(&store?, &name);
+                Ok(db.reset_sync()?)
+            },
+        );
+        Ok(())
+    }
+
+    xpcom_method!(
+        wipe => Wipe(callback: *const mozIBridgedSyncEngineCallback)
+    );
+    fn wipe(&self, callback: &mozIBridgedSyncEngineCallback) -> Result<(), Infallible> {
+        let store = self.store();
+        let name = self.name.clone();
+        self.spawn_void(
+            "skv:KeyValueSyncEngine:Wipe:Request",
+            "skv:KeyValueSyncEngine:Wipe:Response",
+            callback,
+            async move {
+                let db = Database::new(&store?, &name);
+                Ok(db.clear()?)
+            },
+        );
+        Ok(())
+    }
+
+    /// Runs `future` on the blocking pool, resolving `callback` with
+    /// `void` on success. Most `mozIBridgedSyncEngine` methods only
+    /// signal success or failure, with no meaningful result.
+    fn spawn_void(
+        &self,
+        request_name: &'static str,
+        response_name: &'static str,
+        callback: &mozIBridgedSyncEngineCallback,
+        future: impl std::future::Future<Output = Result<(), SyncError>> + Send + 'static,
+    ) {
+        let request = moz_task::spawn_blocking(request_name, future);
+        let callback = RefPtr::new(callback);
+        moz_task::spawn_local(response_name, async move {
+            match request.await {
+                Ok(()) => unsafe { callback.HandleSuccess(false.into_variant().coerce()) },
+                Err(err) => unsafe {
+                    callback.HandleError(err.into(), &*nsCString::from(err.to_string()))
+                },
+            }
+        })
+        .detach();
+    }
+}
+
+fn new_random_sync_id() -> String {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    // A GUID derived from the current time is good enough here: sync IDs
+    // only need to be unlikely to collide across devices, and a
+    // mismatch just triggers a full resync, rather than data loss.
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("skv-{nanos:x}")
+}
+
+impl From<Envelope> for IncomingRecord {
+    fn from(envelope: Envelope) -> Self {
+        IncomingRecord {
+            key: Key::from(envelope.id),
+            value: envelope.data.map(Value::from),
+            server_modified: envelope.modified,
+        }
+    }
+}
+
+impl From<OutgoingRecord> for Envelope {
+    fn from(record: OutgoingRecord) -> Self {
+        Envelope {
+            id: record.key.as_str().to_owned(),
+            data: record.value.map(serde_json::Value::from),
+            modified: 0,
+        }
+    }
+}
+
+/// The error type for interface methods that never return an error.
+enum Infallible {}
+
+impl From<Infallible> for nsresult {
+    fn from(_: Infallible) -> Self {
+        nserror::NS_ERROR_FAILURE
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+enum SyncError {
+    #[error("coordinator: {0}")]
+    Coordinator(#[from] CoordinatorError),
+    #[error("store: {0}")]
+    Store(#[from] StoreError),
+    #[error("database: {0}")]
+    Database(#[from] DatabaseError),
+    #[error("malformed sync envelope: {0}")]
+    Envelope(#[from] serde_json::Error),
+}
+
+impl From<SyncError> for nsresult {
+    fn from(_: SyncError) -> Self {
+        nserror::NS_ERROR_FAILURE
+    }
+}
@@ -0,0 +1,125 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Values stored alongside keys in a database.
+
+use nserror::nsresult;
+use nsstring::nsString;
+use rusqlite::{
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+    ToSql,
+};
+use storage_variant::VariantType;
+use xpcom::{interfaces::nsIVariant, RefPtr};
+
+/// A value stored alongside a key in a named logical database.
+///
+/// Values are stored on disk as SQLite `JSONB`, and are converted to and
+/// from `nsIVariant`s at the XPCOM boundary.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Value(serde_json::Value);
+
+impl Value {
+    pub fn from_variant(variant: &nsIVariant) -> Result<Self, ValueError> {
+        if let Ok(value) = unsafe_get_bool(variant) {
+            return Ok(Value(serde_json::Value::Bool(value)));
+        }
+        if let Ok(value) = unsafe_get_double(variant) {
+            return Ok(Value(
+                serde_json::Number::from_f64(value)
+                    .map(serde_json::Value::Number)
+                    .unwrap_or(serde_json::Value::Null),
+            ));
+        }
+        let mut string = nsString::new();
+        unsafe { variant.GetAsAString(&mut *string) }.to_result()?;
+        Ok(Value(serde_json::Value::String(string.to_string())))
+    }
+
+    /// The length, in bytes, of this value's `JSON` serialization.
+    ///
+    /// Used for quota accounting; see
+    /// [`crate::skv::database::Database::bytes_in_use`].
+    pub fn json_len(&self) -> usize {
+        serde_json::to_string(&self.0).map_or(0, |json| json.len())
+    }
+
+    pub fn to_variant(&self) -> Result<RefPtr<nsIVariant>, ValueError> {
+        Ok(match &self.0 {
+            serde_json::Value::Null => false.into_variant(),
+            serde_json::Value::Bool(value) => value.into_variant(),
+            serde_json::Value::Number(value) => {
+                value.as_f64().ok_or(ValueError::UnsupportedValue)?.into_variant()
+            }
+            serde_json::Value::String(value) => value.as_str().into_variant(),
+            serde_json::Value::Array(_) | serde_json::Value::Object(_) => {
+                return Err(ValueError::UnsupportedValue)
+            }
+        })
+    }
+
+    /// Returns an error if this value is a JSON array or object: the only
+    /// values [`Value::to_variant`] can actually represent are `null`,
+    /// booleans, numbers, and strings, so anything else must be rejected
+    /// on the way in (e.g. by `Database::store_incoming`), rather than
+    /// persisted and fail `to_variant` later, at an arbitrary `get` or
+    /// `enumerate` call.
+    pub fn ensure_scalar(&self) -> Result<(), ValueError> {
+        match &self.0 {
+            serde_json::Value::Array(_) | serde_json::Value::Object(_) => {
+                Err(ValueError::UnsupportedValue)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl From<serde_json::Value> for Value {
+    fn from(value: serde_json::Value) -> Self {
+        Value(value)
+    }
+}
+
+impl From<Value> for serde_json::Value {
+    fn from(value: Value) -> Self {
+        value.0
+    }
+}
+
+fn unsafe_get_bool(variant: &nsIVariant) -> Result<bool, nsresult> {
+    let mut value = false;
+    unsafe { variant.GetAsBool(&mut value) }.to_result()?;
+    Ok(value)
+}
+
+fn unsafe_get_double(variant: &nsIVariant) -> Result<f64, nsresult> {
+    let mut value = 0f64;
+    unsafe { variant.GetAsDouble(&mut value) }.to_result()?;
+    Ok(value)
+}
+
+impl ToSql for Value {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let json = serde_json::to_string(&self.0)
+            .map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))?;
+        Ok(ToSqlOutput::from(json))
+    }
+}
+
+impl FromSql for Value {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let json = value.as_str()?;
+        serde_json::from_str(json)
+            .map(Value)
+            .map_err(|err| FromSqlError::Other(Box::new(err)))
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ValueError {
+    #[error("value type is not supported")]
+    UnsupportedValue,
+    #[error("error code: {0}")]
+    Nsresult(#[from] nsresult),
+}